@@ -1,35 +1,230 @@
 pub mod renderer;
 use colored::Colorize;
-pub use renderer::Renderer;
+pub use renderer::{Outcome, Renderer};
+pub use stereo_audio::{StereoAudio, StereoSample};
 
+mod atomic_file;
+mod encoder;
 mod errors;
+mod extended_wav;
+mod file_template;
+mod highlight;
+mod link;
+mod loudness;
+mod mixer;
+mod overrides;
+mod preview_header;
+mod sidecar;
 mod stereo_audio;
+mod timeline;
+mod window;
 
+use clap::ArgGroup;
 pub use clap::Parser;
+pub use encoder::OutputFormat;
+use encoder::parse_vorbis_quality;
+pub use errors::{AudioError, RendererError};
+use file_template::parse_file_template;
+pub use highlight::HighlightMode;
+use link::LinkMode;
+use renderer::ExistingPreviews;
+use sidecar::ImageKind;
+pub use timeline::SnapMode;
+pub use window::Segment;
+use window::WindowSpec;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(about, long_about = None)]
+#[command(group(ArgGroup::new("window_start").args(["start", "start_p", "start_measure"])))]
+#[command(group(
+    ArgGroup::new("window_end").args(["end", "end_p", "end_measure", "duration", "measures"])
+))]
+#[command(group(ArgGroup::new("vorbis_bitrate").args([
+    "vorbis_quality",
+    "vorbis_bitrate",
+    "vorbis_average_bitrate",
+    "vorbis_max_bitrate"
+])))]
 pub struct Args {
     /// The directory containing songs to process in a batch.
     #[arg(short = 'f', long, required = true)]
     pub songs_folder: Option<String>,
 
-    /// The starting time of the preview (seconds)
-    #[arg(short = 's', long, default_value_t = 20.0)]
-    pub start: f64,
+    #[command(flatten)]
+    pub settings: PreviewSettings,
 
-    /// The ending time of the preview (seconds)
-    #[arg(short = 'e', long, default_value_t = 40.0)]
-    pub end: f64,
+    /// The filename of the preview file. Can include {title}, {artist}, {chart_stem}, {md5} and {sha256} of the chart.
+    #[arg(
+        short = 'o',
+        long,
+        default_value = "preview_auto_generated.ogg",
+        value_parser = parse_file_template
+    )]
+    pub preview_file: String,
+
+    /// Write output files under this folder, in the same folder structure as the songs folder, instead of into song folders.
+    /// Options that change song folders need --link as well.
+    #[arg(long)]
+    pub output_dir: Option<String>,
+
+    /// Link previews written under --output-dir back into their song folders, so players still find them.
+    /// Their --peaks and --image files are linked along with them.
+    #[arg(long, value_enum, requires = "output_dir")]
+    pub link: Option<LinkMode>,
+
+    /// Render the whole chart, keysounds included, into a bounce file instead of a preview.
+    #[arg(long, default_value_t = false)]
+    pub bounce: bool,
+
+    /// The filename of the bounce file, which can include the same fields as the preview file.
+    #[arg(long, default_value = "bounce.ogg", value_parser = parse_file_template)]
+    pub bounce_file: String,
+
+    /// Render the BGM, each player lane and scratch of the chart into separate, time-aligned stem files.
+    #[arg(long, default_value_t = false, conflicts_with = "bounce")]
+    pub stems: bool,
+
+    /// The folder in a song folder that stem files are written to
+    #[arg(long, default_value = "stems")]
+    pub stems_folder: String,
+
+    /// Write the waveform peaks of each preview into a JSON file next to it, in the format of audiowaveform.
+    #[arg(long, default_value_t = false)]
+    pub peaks: bool,
+
+    /// The number of samples that each pair of peaks covers.
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    pub peaks_samples: u32,
+
+    /// Draw a PNG image of each preview next to it.
+    #[arg(long, value_enum)]
+    pub image: Option<ImageKind>,
+
+    /// The width of preview images in pixels.
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    pub image_width: u32,
+
+    /// The height of preview images in pixels.
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    pub image_height: u32,
+
+    /// Generate a preview for charts whose #PREVIEW file is missing.
+    #[arg(long, default_value_t = false)]
+    pub generate_missing_preview: bool,
+
+    /// Re-encode the #PREVIEW files of charts with the fade and volume settings, keeping a backup of the original.
+    /// Previews keep their format unless --format is set, in which case the chart is pointed at the new file.
+    #[arg(long, default_value_t = false)]
+    pub reencode_author_preview: bool,
+
+    /// Write a #PREVIEW header (or preview_music in BMSON) pointing at the generated preview into each chart.
+    /// A backup of the original chart is kept.
+    #[arg(long, default_value_t = false)]
+    pub write_preview_header: bool,
+
+    /// What to do when a song folder already has other preview files that players would pick up.
+    #[arg(long, value_enum, default_value_t = ExistingPreviews::KeepBoth)]
+    pub existing: ExistingPreviews,
+
+    /// Overwrite existing preview files.
+    #[arg(long, default_value_t = false)]
+    pub overwrite: bool,
+
+    /// Process files in serial (avoid multithreading).
+    #[arg(long, default_value_t = false)]
+    pub serial: bool,
+
+    /// The name of the file in a song folder that overrides these settings for that song.
+    #[arg(long, default_value = "preview.toml")]
+    pub override_file: String,
+
+    /// Render each chart in a folder. Unless the preview file includes a field like {chart_stem} or {md5},
+    /// they all share one preview file, and only the first is rendered unless overwriting.
+    #[arg(long, default_value_t = false)]
+    pub render_duplicates: bool,
+}
+
+/// Settings for rendering a preview, which are the options that don't depend on where files are written.
+/// The defaults are the same as the command line's.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PreviewSettings {
+    /// The starting time of the preview (seconds). Defaults to 20 seconds.
+    #[arg(short = 's', long)]
+    pub start: Option<f64>,
+
+    /// The ending time of the preview (seconds). Defaults to 20 seconds after the start.
+    #[arg(short = 'e', long)]
+    pub end: Option<f64>,
 
     /// The starting time of the preview (percentage of song)
     #[arg(long)]
     pub start_p: Option<f64>,
 
-    /// The starting time of the preview (percentage of song)
+    /// The ending time of the preview (percentage of song)
     #[arg(long)]
     pub end_p: Option<f64>,
 
+    /// The starting time of the preview (measure of chart)
+    #[arg(long)]
+    pub start_measure: Option<f64>,
+
+    /// The ending time of the preview (measure of chart)
+    #[arg(long)]
+    pub end_measure: Option<f64>,
+
+    /// The length of the preview (seconds)
+    #[arg(short = 'd', long)]
+    pub duration: Option<f64>,
+
+    /// The length of the preview (measures)
+    #[arg(long)]
+    pub measures: Option<f64>,
+
+    /// Move the preview to start on the strongest nearby note. Applied before snapping.
+    #[arg(long, default_value_t = false)]
+    pub align_onset: bool,
+
+    /// The maximum distance to move the preview when aligning it to a note (seconds)
+    #[arg(long, default_value_t = 0.5)]
+    pub onset_tolerance: f64,
+
+    /// Snap the start and end of the preview to the chart's measures, beats or phrases
+    #[arg(long, value_enum)]
+    pub snap: Option<SnapMode>,
+
+    /// The number of measures in a phrase when snapping to phrases
+    #[arg(long, default_value_t = 4)]
+    pub phrase_length: usize,
+
+    /// Render a medley of segments instead of a single window, such as `0-15,40%-55%,#32-#40`.
+    /// Segments are given in seconds, percentages (%) or measures (#).
+    #[arg(
+        long = "segment",
+        value_delimiter = ',',
+        conflicts_with = "loop_preview"
+    )]
+    pub segments: Vec<Segment>,
+
+    /// The duration of the crossfade between segments of a medley
+    #[arg(long, default_value_t = 1.0)]
+    pub segment_crossfade: f64,
+
+    /// Automatically move the preview to the highlight of the song, keeping its length.
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "density")]
+    pub auto: Option<HighlightMode>,
+
+    /// The weight of BGM notes relative to playable notes when finding the highlight by density
+    #[arg(long, default_value_t = 0.0)]
+    pub auto_bgm_weight: f64,
+
+    /// Render a preview that loops seamlessly, crossfading its end into its start instead of fading
+    #[arg(long, default_value_t = false)]
+    pub loop_preview: bool,
+
+    /// The duration of the crossfade at the loop point of a looping preview
+    #[arg(long, default_value_t = 1.0)]
+    pub loop_crossfade: f64,
+
     /// The duration to fade in the preview
     #[arg(long, default_value_t = 2.0)]
     pub fade_in: f64,
@@ -38,14 +233,30 @@ pub struct Args {
     #[arg(long, default_value_t = 2.0)]
     pub fade_out: f64,
 
-    /// The filename of the preview file
-    #[arg(short = 'o', long, default_value = "preview_auto_generated.ogg")]
-    pub preview_file: String,
+    /// The format of output files. Defaults to the format of the preview file's extension.
+    #[arg(long, value_enum)]
+    pub format: Option<OutputFormat>,
 
     /// Render mono instead of stereo preview audio
     #[arg(short = 'm', long, default_value_t = false)]
     pub mono_audio: bool,
 
+    /// The quality of Vorbis output, from -1 (smallest) to 10 (best). Defaults to 3.
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_vorbis_quality)]
+    pub vorbis_quality: Option<f32>,
+
+    /// The target bitrate of Vorbis output, which varies with the audio (kbps)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub vorbis_bitrate: Option<u32>,
+
+    /// The average bitrate of Vorbis output (kbps)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub vorbis_average_bitrate: Option<u32>,
+
+    /// The maximum average bitrate of Vorbis output, which otherwise varies to keep quality (kbps)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub vorbis_max_bitrate: Option<u32>,
+
     /// The sample rate of the preview file. Defaults to sample rate of the song.
     #[arg(short = 'r', long)]
     pub sample_rate: Option<u32>,
@@ -54,44 +265,82 @@ pub struct Args {
     #[arg(short = 'v', long, default_value_t = 100.0)]
     pub volume: f32,
 
-    /// Overwrite existing preview files.
+    /// Tag output files with ReplayGain (or R128 for Opus) from their loudness, without changing their audio.
+    /// Tags are written before the audio, so each preview is mixed twice: once to measure it, then to encode it.
     #[arg(long, default_value_t = false)]
-    pub overwrite: bool,
+    pub replaygain: bool,
+}
 
-    /// Process files in serial (avoid multithreading).
-    #[arg(long, default_value_t = false)]
-    pub serial: bool,
+impl Default for PreviewSettings {
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            settings: PreviewSettings,
+        }
 
-    /// Render each chart in a folder. Only one preview file will be generated - it will be overwritten by itself.
-    #[arg(long, default_value_t = false)]
-    pub render_duplicates: bool,
+        Defaults::parse_from([env!("CARGO_PKG_NAME")]).settings
+    }
 }
 
 use errors::ProcessError;
+use overrides::SongOverrides;
 use rayon::prelude::*;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use walkdir::{DirEntry, WalkDir};
 
-fn process_song(args: &Args) -> impl Fn(DirEntry) {
-    move |file| {
+fn process_song(args: &Args) -> impl Fn((DirEntry, Option<SongOverrides>)) {
+    move |(file, overrides)| {
         let path = file.path();
         let str_path = path.to_string_lossy();
 
+        // Apply the song's overrides on top of the batch's arguments, if it has any.
+        let song_args = overrides.as_ref().map(|overrides| overrides.apply(args));
+        let args = song_args.as_ref().unwrap_or(args);
+        let overridden = match &overrides {
+            Some(overrides) => format!(" (overrides: {})", overrides.describe()),
+            None => String::new(),
+        };
+
         // Setup (parse) the song file as a renderer
         match Renderer::new(path) {
             // Generate the preview file
-            Ok(render) => match render.process_bms_file(&args) {
-                Ok(_) => {
+            Ok(render) => match render.process_bms_file(args) {
+                Ok(Outcome::Skipped(reason)) => {
+                    println!(
+                        "{} [{}]{}: {}.",
+                        "Skip".yellow(),
+                        str_path,
+                        overridden,
+                        reason
+                    );
+                }
+                Ok(Outcome::Rendered) => {
                     println!(
-                        "{} {}{}{}",
+                        "{} {}{}{}{}",
                         "Success".green(),
                         "[".yellow(),
                         str_path,
                         "]".yellow(),
+                        overridden.cyan(),
                     );
                 }
-                Err(e) => eprintln!("{} [{}]: {}.", "Fail".red(), str_path, e.to_string().red()),
+                Err(AudioError::SilentRender()) => {
+                    eprintln!(
+                        "{} [{}]{}: nothing was played in the preview window.",
+                        "Silent".red(),
+                        str_path,
+                        overridden
+                    )
+                }
+                Err(e) => eprintln!(
+                    "{} [{}]{}: {}.",
+                    "Fail".red(),
+                    str_path,
+                    overridden,
+                    e.to_string().red()
+                ),
             },
             Err(e) => eprintln!("{} [{}]: {}.", "Fail".red(), str_path, e.to_string().red()),
         }
@@ -102,40 +351,87 @@ fn process_song(args: &Args) -> impl Fn(DirEntry) {
 pub fn process_folder(song_folder: &PathBuf, args: &Args) -> Result<(), ProcessError> {
     const VALID_EXTS: [&str; 5] = ["bms", "bme", "bml", "pms", "bmson"];
 
+    // Check the preview window once, rather than failing on every song.
+    WindowSpec::from_settings(&args.settings)?;
+
+    // Song folders are only written to with --output-dir when previews are linked back into them.
+    if args.output_dir.is_some() && args.link.is_none() {
+        let song_folder_writes = [
+            (args.write_preview_header, "--write-preview-header"),
+            (args.reencode_author_preview, "--reencode-author-preview"),
+            (
+                args.existing == ExistingPreviews::Replace,
+                "--existing replace",
+            ),
+        ];
+        if let Some((_, option)) = song_folder_writes.into_iter().find(|(set, _)| *set) {
+            return Err(ProcessError::WritesSongFolders(option));
+        }
+    }
+
     if !song_folder.exists() || !song_folder.is_dir() {
         return Err(ProcessError::InvalidSongsFolder());
     }
 
     // Track folders that have been explored to avoid rendering same song multiple times
     let mut explored_folders: HashSet<PathBuf> = HashSet::new();
+    // Overrides are read once per folder, the first time one of its charts is found.
+    let mut folder_overrides: HashMap<PathBuf, Option<SongOverrides>> = HashMap::new();
     // Get all song files (by extension) in the song folder
-    let bms_files = WalkDir::new(song_folder)
-        .into_iter()
-        .filter_map(|file| {
-            let Ok(file) = file else { return None };
-
-            let path = file.path();
-            let parent = path.parent()?.to_path_buf();
-            let Some(extension) = path.extension() else {
-                return None;
-            };
-
-            // Check if the extension if one of the valid BMS extensions
-            let is_valid = VALID_EXTS
-                .iter()
-                .any(|valid_ext| valid_ext == &extension.to_string_lossy());
-            // If the path is a file, is valid, and is in a folder that hasn't been explored, then
-            // we'll add it to the collection.
-            if path.is_file() && is_valid && !explored_folders.contains(&parent) {
-                if !args.render_duplicates {
-                    explored_folders.insert(parent);
+    let bms_files = WalkDir::new(song_folder).into_iter().filter_map(|file| {
+        let Ok(file) = file else { return None };
+
+        let path = file.path();
+        let parent = path.parent()?.to_path_buf();
+        let extension = path.extension()?;
+
+        // Check if the extension if one of the valid BMS extensions
+        let is_valid = VALID_EXTS
+            .iter()
+            .any(|valid_ext| valid_ext == &extension.to_string_lossy());
+        if !path.is_file() || !is_valid || explored_folders.contains(&parent) {
+            return None;
+        }
+
+        let overrides = folder_overrides
+            .entry(parent.clone())
+            .or_insert_with(|| {
+                match SongOverrides::load(parent.join(&args.override_file)) {
+                    Ok(overrides) => overrides,
+                    Err(e) => {
+                        // A song with broken overrides is skipped, since it likely needs hand tuning.
+                        eprintln!(
+                            "{} [{}]: {}.",
+                            "Fail".red(),
+                            parent.to_string_lossy(),
+                            e.to_string().red()
+                        );
+                        explored_folders.insert(parent.clone());
+                        None
+                    }
                 }
+            })
+            .clone();
+        if explored_folders.contains(&parent) {
+            return None;
+        }
+
+        // If the overrides choose a chart, then only that chart is rendered in this folder.
+        if let Some(chart) = overrides.as_ref().and_then(|o| o.chart.as_ref())
+            && path
+                .file_name()
+                .is_none_or(|name| name.to_string_lossy() != *chart)
+        {
+            return None;
+        }
+
+        // The file is valid and is in a folder that hasn't been explored, so we'll add it to the collection.
+        if !args.render_duplicates {
+            explored_folders.insert(parent);
+        }
 
-                Some(file)
-            } else {
-                None
-            }
-        });
+        Some((file, overrides))
+    });
 
     // Iterate over songs in parallel
     if !args.serial {
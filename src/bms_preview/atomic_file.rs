@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts temporary files, so that files written at the same time get different temporary paths.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Get a temporary path in the same folder as a file, which it can be written to before being moved into place.
+/// The path is hidden and doesn't start with the file's name, so players won't pick it up as a preview.
+/// Temporary files left behind for the same file by runs that were killed are removed.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    remove_stale_temps(path, &name);
+
+    path.with_file_name(format!(".{}.{}-{}.tmp", name, process::id(), count))
+}
+
+/// Write a file through a temporary file that's renamed over it, so the file is either
+/// completely written or left as it was.
+pub fn write_atomic(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temp = temp_path(path);
+
+    let result = fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
+}
+
+/// Remove the temporary files of a file that were made by other processes, which were interrupted
+/// before they could move them into place or clean them up.
+fn remove_stale_temps(path: &Path, name: &str) {
+    let Some(Ok(entries)) = path.parent().map(fs::read_dir) else {
+        return;
+    };
+
+    entries.flatten().for_each(|entry| {
+        if is_stale_temp(&entry.file_name().to_string_lossy(), name, process::id()) {
+            let _ = fs::remove_file(entry.path());
+        }
+    });
+}
+
+/// Check if a file name is a temporary file of a file, as named by `temp_path`, made by another process.
+fn is_stale_temp(file_name: &str, name: &str, pid: u32) -> bool {
+    let Some(suffix) = file_name
+        .strip_prefix('.')
+        .and_then(|rest| rest.strip_prefix(name))
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|rest| rest.strip_suffix(".tmp"))
+    else {
+        return false;
+    };
+    let Some((temp_pid, count)) = suffix.split_once('-') else {
+        return false;
+    };
+
+    count.parse::<usize>().is_ok()
+        && temp_pid
+            .parse::<u32>()
+            .is_ok_and(|temp_pid| temp_pid != pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_temps_are_from_other_processes() {
+        assert!(is_stale_temp(".preview.ogg.1234-0.tmp", "preview.ogg", 42));
+        assert!(is_stale_temp(".preview.ogg.1234-17.tmp", "preview.ogg", 42));
+        assert!(!is_stale_temp(".preview.ogg.42-0.tmp", "preview.ogg", 42));
+    }
+
+    #[test]
+    fn other_files_are_not_stale_temps() {
+        assert!(!is_stale_temp("preview.ogg", "preview.ogg", 42));
+        assert!(!is_stale_temp(
+            ".preview.ogg.1234-0.tmp",
+            "preview.flac",
+            42
+        ));
+        assert!(!is_stale_temp(".preview.ogg.backup.tmp", "preview.ogg", 42));
+        assert!(!is_stale_temp(".preview.ogg.1234.tmp", "preview.ogg", 42));
+        assert!(!is_stale_temp(".preview.ogg.x-0.tmp", "preview.ogg", 42));
+    }
+}
@@ -0,0 +1,440 @@
+mod flac;
+mod ogg_opus;
+mod vorbis;
+mod wav;
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use clap::ValueEnum;
+
+use crate::bms_preview::PreviewSettings;
+use crate::bms_preview::atomic_file::temp_path;
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// The formats that audio can be written in.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Ogg Vorbis (.ogg)
+    Vorbis,
+    /// Ogg Opus (.opus). Always encoded at 48 kHz.
+    Opus,
+    /// 16-bit FLAC (.flac)
+    Flac,
+    /// 16-bit PCM WAV (.wav)
+    Wav16,
+    /// 24-bit PCM WAV (.wav)
+    Wav24,
+    /// 32-bit float WAV (.wav)
+    WavFloat,
+}
+
+impl OutputFormat {
+    /// Get the format that a file extension is usually written in.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "ogg" => Some(OutputFormat::Vorbis),
+            "opus" => Some(OutputFormat::Opus),
+            "flac" => Some(OutputFormat::Flac),
+            "wav" => Some(OutputFormat::Wav16),
+            _ => None,
+        }
+    }
+
+    /// Get the extension of files in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Vorbis => "ogg",
+            OutputFormat::Opus => "opus",
+            OutputFormat::Flac => "flac",
+            OutputFormat::Wav16 | OutputFormat::Wav24 | OutputFormat::WavFloat => "wav",
+        }
+    }
+
+    /// Choose the format of an output file, using the given format or else the file's extension.
+    /// If a format is given, the file's extension is changed to match it.
+    pub fn choose(format: Option<Self>, file_name: &str) -> (Self, String) {
+        let path = Path::new(file_name);
+        let from_extension = path
+            .extension()
+            .and_then(|ext| OutputFormat::from_extension(&ext.to_string_lossy()));
+
+        match format {
+            None => (
+                from_extension.unwrap_or(OutputFormat::Vorbis),
+                file_name.to_string(),
+            ),
+            Some(format)
+                if from_extension.map(|ext| ext.extension()) == Some(format.extension()) =>
+            {
+                (format, file_name.to_string())
+            }
+            Some(format) => (
+                format,
+                path.with_extension(format.extension())
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        }
+    }
+
+    /// Get the sample rate to encode at, for formats that only support certain rates.
+    pub fn sample_rate(&self, sample_rate: u32) -> u32 {
+        match self {
+            OutputFormat::Opus => ogg_opus::OPUS_SAMPLE_RATE,
+            _ => sample_rate,
+        }
+    }
+}
+
+/// How the bitrate of Vorbis output is managed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VorbisBitrate {
+    /// Vary the bitrate to keep a quality level, from -1 to 10.
+    Quality(f32),
+    /// Vary the bitrate around a target, in bits per second.
+    Target(u32),
+    /// Keep to an average bitrate, in bits per second.
+    Average(u32),
+    /// Vary the bitrate to keep quality, but cap the average bitrate, in bits per second.
+    Maximum(u32),
+}
+
+/// Parse a Vorbis quality level, which must be between -1 and 10.
+pub fn parse_vorbis_quality(s: &str) -> Result<f32, String> {
+    let quality: f32 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid quality '{}'", s))?;
+    if !(-1.0..=10.0).contains(&quality) {
+        return Err("quality must be between -1 and 10".to_string());
+    }
+
+    Ok(quality)
+}
+
+/// The name and version of the generator, which is written into output files.
+pub const VENDOR: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+
+/// Tags written into output files, as Vorbis comment field names and values.
+pub type Tags = Vec<(String, String)>;
+
+/// Settings for encoding output files, which apply to every format unless noted.
+#[derive(Clone, Debug, Default)]
+pub struct EncoderSettings {
+    pub mono: bool,
+    /// Uses the encoder's default when None.
+    pub vorbis_bitrate: Option<VorbisBitrate>,
+    pub tags: Tags,
+}
+
+impl EncoderSettings {
+    /// Get the encoder settings from the preview settings.
+    pub fn from_preview_settings(settings: &PreviewSettings) -> Self {
+        let kbps = |kbps: u32| kbps * 1000;
+        let vorbis_bitrate = settings
+            .vorbis_quality
+            .map(VorbisBitrate::Quality)
+            .or(settings.vorbis_bitrate.map(kbps).map(VorbisBitrate::Target))
+            .or(settings
+                .vorbis_average_bitrate
+                .map(kbps)
+                .map(VorbisBitrate::Average))
+            .or(settings
+                .vorbis_max_bitrate
+                .map(kbps)
+                .map(VorbisBitrate::Maximum));
+
+        Self {
+            mono: settings.mono_audio,
+            vorbis_bitrate,
+            tags: Tags::new(),
+        }
+    }
+}
+
+/// The number of blocks that can be waiting to be encoded before mixing waits for the encoder to catch up.
+const ENCODE_QUEUE_LENGTH: usize = 4;
+
+/// Encodes audio into a file as it's written, so that it doesn't have to be held in memory as a whole.
+pub trait Encoder: Send {
+    /// Encode a block of samples, of any length.
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError>;
+
+    /// Flush the encoder and finish the file.
+    fn finish(self: Box<Self>) -> Result<(), AudioError>;
+}
+
+/// Where encoders write to, such as a file or a buffer in memory.
+/// Some formats go back to update their headers once they're finished, so it needs to be seekable.
+pub trait EncoderOutput: Write + Seek + Send + 'static {}
+
+impl<T: Write + Seek + Send + 'static> EncoderOutput for T {}
+
+/// A writer with shared ownership, so it can be taken back from an encoder that doesn't return it once it's finished.
+pub struct SharedWriter<W>(Arc<Mutex<W>>);
+
+impl<W> SharedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Take the writer back, which only works once every other owner has been dropped.
+    pub fn into_inner(self) -> Option<W> {
+        let mutex = Arc::into_inner(self.0)?;
+        Some(mutex.into_inner().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, W> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl<W: Seek> Seek for SharedWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.lock().seek(pos)
+    }
+}
+
+/// Writes the output of an encoder to a temporary file, which is moved into place once it's finished.
+/// This way, an output file is either complete or absent, even if encoding fails or is interrupted.
+struct AtomicEncoder {
+    encoder: Option<Box<dyn Encoder>>,
+    temp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl Encoder for AtomicEncoder {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.write(samples),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), AudioError> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+        }
+        fs::rename(&self.temp_path, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl Drop for AtomicEncoder {
+    fn drop(&mut self) {
+        // Close the temporary file before removing it. If the encoder was finished, it's already been moved.
+        self.encoder.take();
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+/// A message to an encoder thread.
+enum EncodeMessage {
+    Block(Vec<StereoSample>),
+    Finish,
+}
+
+/// Runs an encoder on its own thread, so that mixing the next block overlaps with encoding the last.
+/// If it's dropped without being finished, the encoder is dropped without finishing too.
+struct ThreadedEncoder {
+    sender: Option<SyncSender<EncodeMessage>>,
+    thread: Option<JoinHandle<Result<(), AudioError>>>,
+}
+
+impl ThreadedEncoder {
+    fn spawn(mut encoder: Box<dyn Encoder>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(ENCODE_QUEUE_LENGTH);
+        let thread = thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    EncodeMessage::Block(samples) => encoder.write(&samples)?,
+                    EncodeMessage::Finish => return encoder.finish(),
+                }
+            }
+
+            Ok(())
+        });
+
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// Send a message to the thread.
+    /// If the thread has already stopped, it's because encoding failed, so its error is returned instead.
+    fn send(&mut self, message: EncodeMessage) -> Result<(), AudioError> {
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.send(message).is_ok());
+        if !sent {
+            return self.join();
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the thread to stop, and get its result.
+    fn join(&mut self) -> Result<(), AudioError> {
+        // Closing the channel stops the thread, if it's still waiting for blocks.
+        self.sender.take();
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or(Err(AudioError::EncoderPanicked())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Encoder for ThreadedEncoder {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        self.send(EncodeMessage::Block(samples.to_vec()))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), AudioError> {
+        self.send(EncodeMessage::Finish)?;
+        self.join()
+    }
+}
+
+impl Drop for ThreadedEncoder {
+    fn drop(&mut self) {
+        // Wait for the encoder to be dropped, so its temporary file is removed before we move on.
+        let _ = self.join();
+    }
+}
+
+/// Create an output file and setup an encoder of a format to encode into it.
+/// The file only appears at its path once the encoder is finished.
+/// Encoding happens on another thread, and writes only wait if the encoder falls behind.
+pub fn create_encoder(
+    path: impl AsRef<Path>,
+    format: OutputFormat,
+    sample_rate: u32,
+    settings: &EncoderSettings,
+) -> Result<Box<dyn Encoder>, AudioError> {
+    let path = path.as_ref();
+    let temp_path = temp_path(path);
+    let encoder = File::create(&temp_path)
+        .map_err(AudioError::from)
+        .and_then(|file| {
+            create_format_encoder(BufWriter::new(file), format, sample_rate, settings)
+        });
+
+    // Clean up anything the encoder created before it failed.
+    match encoder {
+        Ok(encoder) => Ok(Box::new(ThreadedEncoder::spawn(Box::new(AtomicEncoder {
+            encoder: Some(encoder),
+            temp_path,
+            path: path.to_path_buf(),
+        })))),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Setup an encoder of a format to encode into any output, such as a buffer in memory.
+/// Encoding happens on another thread, and the output is only complete once the encoder is finished.
+pub fn create_output_encoder(
+    output: impl EncoderOutput,
+    format: OutputFormat,
+    sample_rate: u32,
+    settings: &EncoderSettings,
+) -> Result<Box<dyn Encoder>, AudioError> {
+    let encoder = create_format_encoder(output, format, sample_rate, settings)?;
+    Ok(Box::new(ThreadedEncoder::spawn(encoder)))
+}
+
+/// Setup an encoder of a format to encode into an output.
+fn create_format_encoder(
+    output: impl EncoderOutput,
+    format: OutputFormat,
+    sample_rate: u32,
+    settings: &EncoderSettings,
+) -> Result<Box<dyn Encoder>, AudioError> {
+    // If we're encoding in mono, we'll need to tell the encoder.
+    let channels = if settings.mono { 1 } else { 2 };
+
+    Ok(match format {
+        OutputFormat::Vorbis => Box::new(vorbis::VorbisEncoder::create(
+            output,
+            sample_rate,
+            channels,
+            settings.vorbis_bitrate,
+            &settings.tags,
+        )?),
+        OutputFormat::Opus => Box::new(ogg_opus::OpusEncoder::create(
+            output,
+            sample_rate,
+            channels,
+            &settings.tags,
+        )?),
+        OutputFormat::Flac => Box::new(flac::FlacEncoder::create(
+            output,
+            sample_rate,
+            channels,
+            &settings.tags,
+        )?),
+        OutputFormat::Wav16 | OutputFormat::Wav24 | OutputFormat::WavFloat => Box::new(
+            wav::WavEncoder::create(output, format, sample_rate, channels, settings.tags.clone())?,
+        ),
+    })
+}
+
+/// Convert samples into interleaved frames with the given number of channels.
+/// In mono, the channels are averaged.
+fn interleave(samples: &[StereoSample], channels: u16) -> Vec<f32> {
+    if channels == 1 {
+        samples
+            .iter()
+            .map(|sample| (sample.left + sample.right) / 2.0)
+            .collect()
+    } else {
+        samples
+            .iter()
+            .flat_map(|sample| [sample.left, sample.right])
+            .collect()
+    }
+}
+
+/// Build a Vorbis comment header without its framing bit, which is how Opus and FLAC store tags too.
+fn vorbis_comment(tags: &Tags) -> Vec<u8> {
+    fn push_string(header: &mut Vec<u8>, string: &str) {
+        header.extend_from_slice(&(string.len() as u32).to_le_bytes());
+        header.extend_from_slice(string.as_bytes());
+    }
+
+    let mut header = Vec::new();
+    push_string(&mut header, VENDOR);
+    header.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+    tags.iter().for_each(|(name, value)| {
+        push_string(&mut header, &format!("{}={}", name, value));
+    });
+
+    header
+}
@@ -0,0 +1,250 @@
+use std::fmt::Display;
+use std::io::{SeekFrom, Write};
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Fill, FrameBuf};
+use md5::{Digest, Md5};
+
+use crate::bms_preview::encoder::{Encoder, EncoderOutput, Tags, interleave, vorbis_comment};
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+const FLAC_BITS_PER_SAMPLE: usize = 16;
+const STREAM_INFO_BLOCK: u8 = 0;
+const STREAM_INFO_LENGTH: usize = 34;
+const VORBIS_COMMENT_BLOCK: u8 = 4;
+const LAST_BLOCK_FLAG: u8 = 0x80;
+
+/// Encodes 16-bit FLAC files.
+/// Each block is encoded into a frame as soon as it fills, and the stream info is filled in once
+/// the file is finished.
+pub struct FlacEncoder<W: Write> {
+    output: W,
+    config: Verified<config::Encoder>,
+    stream_info: StreamInfo,
+    sample_rate: u32,
+    channels: u16,
+    /// Interleaved samples that haven't made up a whole block yet.
+    pending: Vec<i32>,
+    frames: usize,
+    /// The number of samples in each channel encoded so far.
+    length: u64,
+    /// The smallest and largest frames written so far, in bytes.
+    frame_sizes: Option<(usize, usize)>,
+    /// The MD5 of the encoded samples, which decoders use to check the file.
+    md5: Md5,
+}
+
+impl<W: Write> FlacEncoder<W> {
+    pub fn create(
+        mut output: W,
+        sample_rate: u32,
+        channels: u16,
+        tags: &Tags,
+    ) -> Result<Self, AudioError> {
+        let config = config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| flac_error(e))?;
+        let stream_info = StreamInfo::new(
+            sample_rate as usize,
+            channels as usize,
+            FLAC_BITS_PER_SAMPLE,
+        )
+        .map_err(flac_error)?;
+
+        // The stream info isn't known until the end, so leave space for it.
+        output.write_all(&stream_header(tags))?;
+
+        Ok(Self {
+            output,
+            config,
+            stream_info,
+            sample_rate,
+            channels,
+            pending: Vec::new(),
+            frames: 0,
+            length: 0,
+            frame_sizes: None,
+            md5: Md5::new(),
+        })
+    }
+
+    /// Encode a block of interleaved samples as the next frame.
+    fn encode_block(&mut self, samples: &[i32]) -> Result<(), AudioError> {
+        let block_size = samples.len() / self.channels as usize;
+        let mut frame_buffer =
+            FrameBuf::with_size(self.channels as usize, block_size).map_err(flac_error)?;
+        frame_buffer.fill_interleaved(samples).map_err(flac_error)?;
+
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &frame_buffer,
+            self.frames,
+            &self.stream_info,
+        )
+        .map_err(flac_error)?;
+        let mut sink = ByteSink::new();
+        frame.write(&mut sink).map_err(flac_error)?;
+        self.output.write_all(sink.as_slice())?;
+
+        let size = sink.as_slice().len();
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size),
+        });
+        samples
+            .iter()
+            .for_each(|sample| self.md5.update((*sample as i16).to_le_bytes()));
+        self.frames += 1;
+        self.length += block_size as u64;
+
+        Ok(())
+    }
+}
+
+impl<W: EncoderOutput> Encoder for FlacEncoder<W> {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        let max_int = ((1 << (FLAC_BITS_PER_SAMPLE - 1)) - 1) as f32;
+
+        // Clip rather than wrap around when the mix is too loud.
+        self.pending.extend(
+            interleave(samples, self.channels)
+                .into_iter()
+                .map(|sample| (sample.clamp(-1.0, 1.0) * max_int).round() as i32),
+        );
+
+        // Encode every whole block, and keep the rest for the next block.
+        let pending = std::mem::take(&mut self.pending);
+        let mut blocks = pending.chunks_exact(self.config.block_size * self.channels as usize);
+        for block in &mut blocks {
+            self.encode_block(block)?;
+        }
+        self.pending = blocks.remainder().to_vec();
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), AudioError> {
+        // The last frame is allowed to be shorter than the others.
+        let pending = std::mem::take(&mut self.pending);
+        if !pending.is_empty() {
+            self.encode_block(&pending)?;
+        }
+
+        let info = StreamInfoBlock {
+            block_size: self.config.block_size as u16,
+            frame_sizes: self.frame_sizes.unwrap_or_default(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            length: self.length,
+            md5: self.md5.finalize().into(),
+        };
+        self.output.seek(SeekFrom::Start(8))?;
+        self.output.write_all(&info.to_bytes())?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The contents of the stream info block, which describes the whole stream.
+struct StreamInfoBlock {
+    block_size: u16,
+    /// The smallest and largest frames in bytes, or zero when unknown.
+    frame_sizes: (usize, usize),
+    sample_rate: u32,
+    channels: u16,
+    length: u64,
+    md5: [u8; 16],
+}
+
+impl StreamInfoBlock {
+    fn to_bytes(&self) -> [u8; STREAM_INFO_LENGTH] {
+        let mut bytes = [0; STREAM_INFO_LENGTH];
+        bytes[0..2].copy_from_slice(&self.block_size.to_be_bytes());
+        bytes[2..4].copy_from_slice(&self.block_size.to_be_bytes());
+        bytes[4..7].copy_from_slice(&(self.frame_sizes.0 as u32).to_be_bytes()[1..]);
+        bytes[7..10].copy_from_slice(&(self.frame_sizes.1 as u32).to_be_bytes()[1..]);
+
+        // The sample rate (20 bits), channels - 1 (3 bits), bits per sample - 1 (5 bits) and the
+        // number of samples in each channel (36 bits) are packed together.
+        let packed = (self.sample_rate as u64) << 44
+            | (self.channels as u64 - 1) << 41
+            | (FLAC_BITS_PER_SAMPLE as u64 - 1) << 36
+            | self.length.min((1 << 36) - 1);
+        bytes[10..18].copy_from_slice(&packed.to_be_bytes());
+        bytes[18..34].copy_from_slice(&self.md5);
+
+        bytes
+    }
+}
+
+/// Get the start of a FLAC stream, with an empty stream info block followed by a Vorbis comment block.
+fn stream_header(tags: &Tags) -> Vec<u8> {
+    let comment = vorbis_comment(tags);
+
+    // Each block has a one byte header, with a flag for the last block, followed by a 24-bit length.
+    let mut header = b"fLaC".to_vec();
+    header.push(STREAM_INFO_BLOCK);
+    header.extend_from_slice(&(STREAM_INFO_LENGTH as u32).to_be_bytes()[1..]);
+    header.extend_from_slice(&[0; STREAM_INFO_LENGTH]);
+    header.push(VORBIS_COMMENT_BLOCK | LAST_BLOCK_FLAG);
+    header.extend_from_slice(&(comment.len() as u32).to_be_bytes()[1..]);
+    header.extend(comment);
+
+    header
+}
+
+fn flac_error(error: impl Display) -> AudioError {
+    AudioError::FlacEncodingError(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_has_stream_info_then_tags() {
+        let tags = vec![("TITLE".to_string(), "Song".to_string())];
+        let header = stream_header(&tags);
+        let comment = vorbis_comment(&tags);
+
+        assert!(header.starts_with(b"fLaC"));
+        assert_eq!(header[4], STREAM_INFO_BLOCK);
+        assert_eq!(header[5..8], [0, 0, STREAM_INFO_LENGTH as u8]);
+
+        let tags_start = 8 + STREAM_INFO_LENGTH;
+        assert_eq!(header[tags_start], VORBIS_COMMENT_BLOCK | LAST_BLOCK_FLAG);
+        assert_eq!(
+            header[tags_start + 1..tags_start + 4],
+            (comment.len() as u32).to_be_bytes()[1..]
+        );
+        assert_eq!(header[tags_start + 4..], comment[..]);
+    }
+
+    #[test]
+    fn stream_info_is_packed() {
+        let info = StreamInfoBlock {
+            block_size: 4096,
+            frame_sizes: (14, 12345),
+            sample_rate: 44100,
+            channels: 2,
+            length: 441000,
+            md5: [7; 16],
+        };
+        let bytes = info.to_bytes();
+
+        assert_eq!(bytes[0..4], [0x10, 0x00, 0x10, 0x00]);
+        assert_eq!(bytes[4..10], [0, 0, 14, 0, 0x30, 0x39]);
+        // 44100 Hz is 0x0AC44, followed by 0b001 for stereo and 0b01111 for 16 bits.
+        assert_eq!(bytes[10..13], [0x0A, 0xC4, 0x42]);
+        assert_eq!(bytes[13] >> 4, 0xF);
+        assert_eq!(bytes[13] & 0xF, 0);
+        assert_eq!(bytes[14..18], 441000_u32.to_be_bytes());
+        assert_eq!(bytes[18..34], [7; 16]);
+    }
+}
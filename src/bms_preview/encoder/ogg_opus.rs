@@ -0,0 +1,173 @@
+use std::io::Write;
+
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use opus::{Application, Channels};
+
+use crate::bms_preview::encoder::{Encoder, EncoderOutput, Tags, interleave, vorbis_comment};
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// Opus always decodes at 48 kHz, so we encode at it too.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+/// 20 ms frames, the default for music.
+const OPUS_FRAME_SIZE: usize = 960;
+/// The largest packet that the encoder is allowed to output, as recommended by libopus.
+const MAX_PACKET_SIZE: usize = 4000;
+/// There's only one stream in the file, so its serial number doesn't need to be unique.
+const STREAM_SERIAL: u32 = 0x4F707573;
+
+/// Encodes Ogg Opus files.
+pub struct OpusEncoder<W: Write> {
+    encoder: opus::Encoder,
+    writer: PacketWriter<'static, W>,
+    channels: u16,
+    /// Interleaved samples that haven't made up a whole frame yet.
+    pending: Vec<f32>,
+    /// The number of samples that players skip at the start, to make up for the encoder's delay.
+    pre_skip: u64,
+    /// The number of samples encoded so far, which is the granule position of the last packet.
+    position: u64,
+}
+
+impl<W: Write> OpusEncoder<W> {
+    pub fn create(
+        output: W,
+        sample_rate: u32,
+        channels: u16,
+        tags: &Tags,
+    ) -> Result<Self, AudioError> {
+        if sample_rate != OPUS_SAMPLE_RATE {
+            return Err(AudioError::InvalidCodecInfo());
+        }
+
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+        let mut encoder = opus::Encoder::new(OPUS_SAMPLE_RATE, opus_channels, Application::Audio)?;
+        let pre_skip = encoder.get_lookahead()? as u16;
+
+        let mut writer = PacketWriter::new(output);
+
+        // The identification header (RFC 7845, section 5.1), using the default channel mapping.
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels as u8);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&OPUS_SAMPLE_RATE.to_le_bytes());
+        head.extend_from_slice(&0_i16.to_le_bytes());
+        head.push(0);
+        writer.write_packet(head, STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        // The comment header (RFC 7845, section 5.2).
+        let mut comments = b"OpusTags".to_vec();
+        comments.extend(vorbis_comment(tags));
+        writer.write_packet(comments, STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        Ok(Self {
+            encoder,
+            writer,
+            channels,
+            pending: Vec::new(),
+            pre_skip: pre_skip as u64,
+            position: 0,
+        })
+    }
+
+    /// Encode a single frame of interleaved samples into a packet, which ends at a granule position.
+    fn encode_frame(
+        &mut self,
+        frame: &[f32],
+        end: PacketWriteEndInfo,
+        granule_position: u64,
+    ) -> Result<(), AudioError> {
+        let mut packet = vec![0; MAX_PACKET_SIZE];
+        let length = self.encoder.encode_float(frame, &mut packet)?;
+        packet.truncate(length);
+
+        self.writer
+            .write_packet(packet, STREAM_SERIAL, end, granule_position)?;
+
+        Ok(())
+    }
+}
+
+impl<W: EncoderOutput> Encoder for OpusEncoder<W> {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        self.pending.extend(interleave(samples, self.channels));
+
+        // Encode every whole frame, and keep the rest for the next block.
+        let pending = std::mem::take(&mut self.pending);
+        let mut frames = pending.chunks_exact(OPUS_FRAME_SIZE * self.channels as usize);
+        for frame in &mut frames {
+            self.position += OPUS_FRAME_SIZE as u64;
+            self.encode_frame(frame, PacketWriteEndInfo::NormalPacket, self.position)?;
+        }
+        self.pending = frames.remainder().to_vec();
+
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), AudioError> {
+        // The encoder lags behind its input by the pre-skip, so keep encoding silence until all of
+        // the audio has come out. The last granule position marks where the audio really ends, so
+        // players trim the padding.
+        let frame_length = OPUS_FRAME_SIZE * self.channels as usize;
+        let length = self.position + (self.pending.len() / self.channels as usize) as u64;
+        let frames = frames_needed(length, self.pre_skip);
+        let mut frame = std::mem::take(&mut self.pending);
+
+        loop {
+            frame.resize(frame_length, 0.0);
+            self.position += OPUS_FRAME_SIZE as u64;
+            if self.position / OPUS_FRAME_SIZE as u64 >= frames {
+                break;
+            }
+            self.encode_frame(&frame, PacketWriteEndInfo::NormalPacket, self.position)?;
+            frame.clear();
+        }
+
+        let granule_position = end_granule_position(length, self.pre_skip);
+        self.encode_frame(&frame, PacketWriteEndInfo::EndStream, granule_position)?;
+        self.writer.into_inner().flush()?;
+
+        Ok(())
+    }
+}
+
+/// Get the number of frames needed to encode a number of samples, including the pre-skip, so that
+/// none of them are cut off by the encoder's delay.
+fn frames_needed(length: u64, pre_skip: u64) -> u64 {
+    (length + pre_skip).div_ceil(OPUS_FRAME_SIZE as u64)
+}
+
+/// Get the granule position of the last packet, which counts the pre-skip as well as the audio.
+fn end_granule_position(length: u64, pre_skip: u64) -> u64 {
+    pre_skip + length
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_cover_the_pre_skip() {
+        assert_eq!(frames_needed(0, 312), 1);
+        assert_eq!(frames_needed(648, 312), 1);
+        assert_eq!(frames_needed(649, 312), 2);
+        assert_eq!(frames_needed(960, 312), 2);
+        assert_eq!(frames_needed(48000, 312), 51);
+    }
+
+    #[test]
+    fn end_granule_position_is_within_the_last_frame() {
+        [0, 1, 648, 649, 960, 48000].into_iter().for_each(|length| {
+            let pre_skip = 312;
+            let granule_position = end_granule_position(length, pre_skip);
+            let decoded = frames_needed(length, pre_skip) * OPUS_FRAME_SIZE as u64;
+            assert!(granule_position <= decoded);
+            assert!(granule_position > decoded - OPUS_FRAME_SIZE as u64);
+        });
+    }
+}
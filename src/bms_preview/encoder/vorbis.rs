@@ -0,0 +1,89 @@
+use std::{
+    io::Write,
+    num::{NonZeroU8, NonZeroU32},
+};
+
+use vorbis_rs::{VorbisBitrateManagementStrategy, VorbisEncoderBuilder};
+
+use crate::bms_preview::encoder::{Encoder, EncoderOutput, Tags, VorbisBitrate};
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// Encodes Ogg Vorbis files.
+pub struct VorbisEncoder<W: Write> {
+    encoder: vorbis_rs::VorbisEncoder<W>,
+    channels: u16,
+}
+
+impl<W: Write> VorbisEncoder<W> {
+    pub fn create(
+        output: W,
+        sample_rate: u32,
+        channels: u16,
+        bitrate: Option<VorbisBitrate>,
+        tags: &Tags,
+    ) -> Result<Self, AudioError> {
+        let mut builder = VorbisEncoderBuilder::new(
+            NonZeroU32::new(sample_rate).ok_or(AudioError::InvalidCodecInfo())?,
+            NonZeroU8::new(channels as u8).ok_or(AudioError::InvalidCodecInfo())?,
+            output,
+        )?;
+
+        if let Some(bitrate) = bitrate {
+            let bits =
+                |bitrate: u32| NonZeroU32::new(bitrate).ok_or(AudioError::InvalidCodecInfo());
+            builder.bitrate_management_strategy(match bitrate {
+                // libvorbis takes quality from -0.1 to 1, rather than the -1 to 10 of oggenc.
+                VorbisBitrate::Quality(quality) => VorbisBitrateManagementStrategy::QualityVbr {
+                    target_quality: quality / 10.0,
+                },
+                VorbisBitrate::Target(bitrate) => VorbisBitrateManagementStrategy::Vbr {
+                    target_bitrate: bits(bitrate)?,
+                },
+                VorbisBitrate::Average(bitrate) => VorbisBitrateManagementStrategy::Abr {
+                    average_bitrate: bits(bitrate)?,
+                },
+                VorbisBitrate::Maximum(bitrate) => {
+                    VorbisBitrateManagementStrategy::ConstrainedAbr {
+                        maximum_bitrate: bits(bitrate)?,
+                    }
+                }
+            });
+        }
+        tags.iter().for_each(|(name, value)| {
+            builder.comment_tag(name, value);
+        });
+        let encoder = builder.build()?;
+
+        Ok(Self { encoder, channels })
+    }
+}
+
+impl<W: EncoderOutput> Encoder for VorbisEncoder<W> {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        // The encoder takes each channel separately. In mono, we need to average the samples.
+        if self.channels == 1 {
+            let average: Vec<f32> = samples
+                .iter()
+                .map(|sample| (sample.left + sample.right) / 2.0)
+                .collect();
+            self.encoder.encode_audio_block([average])?;
+        } else {
+            let left: Vec<f32> = samples.iter().map(|sample| sample.left).collect();
+            let right: Vec<f32> = samples.iter().map(|sample| sample.right).collect();
+            self.encoder.encode_audio_block([left, right])?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), AudioError> {
+        self.encoder.finish()?.flush()?;
+
+        Ok(())
+    }
+}
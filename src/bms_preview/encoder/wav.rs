@@ -0,0 +1,134 @@
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::bms_preview::encoder::{
+    Encoder, EncoderOutput, OutputFormat, SharedWriter, Tags, interleave,
+};
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// Encodes PCM WAV files, in 16-bit or 24-bit integers or 32-bit floats.
+pub struct WavEncoder<W: Write + Seek> {
+    writer: WavWriter<BufWriter<SharedWriter<W>>>,
+    /// The writer doesn't give back its output when it's finished, so it's shared to append tags afterwards.
+    output: SharedWriter<W>,
+    channels: u16,
+    /// The largest integer sample, or None when writing floats.
+    max_int: Option<f32>,
+    tags: Tags,
+}
+
+impl<W: Write + Seek> WavEncoder<W> {
+    pub fn create(
+        output: W,
+        format: OutputFormat,
+        sample_rate: u32,
+        channels: u16,
+        tags: Tags,
+    ) -> Result<Self, AudioError> {
+        let (bits_per_sample, sample_format) = match format {
+            OutputFormat::Wav24 => (24, SampleFormat::Int),
+            OutputFormat::WavFloat => (32, SampleFormat::Float),
+            _ => (16, SampleFormat::Int),
+        };
+        let spec = WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        };
+
+        let max_int = (sample_format == SampleFormat::Int)
+            .then(|| ((1_i32 << (bits_per_sample - 1)) - 1) as f32);
+
+        let output = SharedWriter::new(output);
+        Ok(Self {
+            writer: WavWriter::new(BufWriter::new(output.clone()), spec)?,
+            output,
+            channels,
+            max_int,
+            tags,
+        })
+    }
+}
+
+impl<W: EncoderOutput> Encoder for WavEncoder<W> {
+    fn write(&mut self, samples: &[StereoSample]) -> Result<(), AudioError> {
+        for sample in interleave(samples, self.channels) {
+            match self.max_int {
+                // Clip rather than wrap around when the mix is too loud.
+                Some(max_int) => self
+                    .writer
+                    .write_sample((sample.clamp(-1.0, 1.0) * max_int).round() as i32)?,
+                None => self.writer.write_sample(sample)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), AudioError> {
+        // The header is written with the final length once the file is finished.
+        let WavEncoder {
+            writer,
+            output,
+            tags,
+            ..
+        } = *self;
+        writer.finalize()?;
+        let mut output = output
+            .into_inner()
+            .ok_or_else(|| io::Error::other("wav output is still in use"))?;
+
+        // The writer doesn't support tags, so they're appended after the audio, and the size of
+        // the RIFF chunk is updated to include them.
+        if !tags.is_empty() {
+            // The audio chunk may need a padding byte before the next chunk.
+            if output.seek(SeekFrom::End(0))? % 2 == 1 {
+                output.write_all(&[0])?;
+            }
+            output.write_all(&info_chunk(&tags))?;
+            let riff_size = output.stream_position()? - 8;
+            output.seek(SeekFrom::Start(4))?;
+            output.write_all(&(riff_size as u32).to_le_bytes())?;
+        }
+        output.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Build a LIST chunk of INFO tags. Tags without an INFO equivalent are put in the comment.
+fn info_chunk(tags: &Tags) -> Vec<u8> {
+    let mut info = b"INFO".to_vec();
+    let mut push_tag = |id: &[u8; 4], value: &str| {
+        // Values are null-terminated, and chunks are padded to an even length.
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+
+        info.extend_from_slice(id);
+        info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if value.len() % 2 == 1 {
+            value.push(0);
+        }
+        info.extend(value);
+    };
+
+    let mut comments: Vec<String> = Vec::new();
+    tags.iter().for_each(|(name, value)| match name.as_str() {
+        "TITLE" => push_tag(b"INAM", value),
+        "ARTIST" => push_tag(b"IART", value),
+        "GENRE" => push_tag(b"IGNR", value),
+        "ENCODER" => push_tag(b"ISFT", value),
+        _ => comments.push(format!("{}={}", name, value)),
+    });
+    if !comments.is_empty() {
+        push_tag(b"ICMT", &comments.join("\n"));
+    }
+
+    let mut chunk = b"LIST".to_vec();
+    chunk.extend_from_slice(&(info.len() as u32).to_le_bytes());
+    chunk.extend(info);
+    chunk
+}
@@ -13,6 +13,53 @@ pub enum ProcessError {
     FailedSongIO(#[from] io::Error),
     #[error("renderer failed: {0}")]
     RendererFailed(#[from] RendererError),
+    #[error("invalid preview window: {0}")]
+    InvalidWindow(#[from] WindowError),
+    #[error("{0} writes into song folders, so it needs --link when --output-dir is set")]
+    WritesSongFolders(&'static str),
+}
+
+#[derive(Error, Debug)]
+pub enum HeaderError {
+    #[error("failed to decode chart")]
+    Undecodable(),
+    #[error("preview file name can't be written in the chart's encoding")]
+    Unencodable(),
+    #[error("bmson chart has no info")]
+    MissingBmsonInfo(),
+    #[error("failed to parse bmson chart: {0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    IOError(#[from] io::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum OverrideError {
+    #[error("failed to read override file: {0}")]
+    ReadError(#[from] io::Error),
+    #[error("failed to parse override file: {0}")]
+    ParseError(#[from] toml::de::Error),
+    #[error("the override file chooses the chart '{0}', which isn't in the song folder")]
+    MissingChart(String),
+}
+
+#[derive(Error, Debug)]
+pub enum WindowError {
+    /// The command line stops these with argument groups, so they only come from override files.
+    #[error("only one of start, start_p and start_measure can be set")]
+    ConflictingStart(),
+    #[error("only one of end, end_p, end_measure, duration and measures can be set")]
+    ConflictingEnd(),
+    #[error("a start, end and duration can't all be set")]
+    OverdeterminedWindow(),
+    #[error("percentages must be between 0 and 100")]
+    InvalidPercentage(),
+    #[error("the duration must be positive")]
+    InvalidDuration(),
+    #[error("the preview must end after it starts")]
+    EmptyWindow(),
+    #[error("the preview would end at {1:.3}s, which isn't after it starts at {0:.3}s")]
+    EndsBeforeStart(f64, f64),
 }
 
 #[derive(Error, Debug)]
@@ -49,6 +96,26 @@ pub enum AudioError {
     IOError(#[from] io::Error),
     #[error("audio decoder error: {0}")]
     DecodingError(#[from] symphonia::core::errors::Error),
+    #[error("invalid preview window: {0}")]
+    InvalidWindow(#[from] WindowError),
+    #[error("failed to write preview header: {0}")]
+    HeaderWriteError(#[from] HeaderError),
+    #[error("rendered preview is silent")]
+    SilentRender(),
     #[error("vorbis encoder error: {0}")]
     VorbisEncodingError(#[from] VorbisError),
+    #[error("opus encoder error: {0}")]
+    OpusEncodingError(#[from] opus::Error),
+    #[error("flac encoder error: {0}")]
+    FlacEncodingError(String),
+    #[error("wav encoder error: {0}")]
+    WavEncodingError(#[from] hound::Error),
+    #[error("encoder thread panicked")]
+    EncoderPanicked(),
+    #[error("encoder output is still in use")]
+    OutputInUse(),
+    #[error("failed to encode peaks: {0}")]
+    PeaksEncodingError(#[from] serde_json::Error),
+    #[error("failed to encode image: {0}")]
+    ImageEncodingError(#[from] png::EncodingError),
 }
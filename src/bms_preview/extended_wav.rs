@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+
+use crate::bms_preview::stereo_audio::StereoAudio;
+
+/// The pitch value of #WAVCMD that plays a sound at its original pitch.
+const WAVCMD_BASE_PITCH: i32 = 60;
+
+/// Modifiers applied to a sound through #EXWAV and #WAVCMD definitions.
+/// Values are kept in their raw integer form so sounds can be grouped by them.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SoundModifier {
+    /// Pan from -10000 (left) to 10000 (right), in DirectSound units.
+    pub pan: i32,
+    /// Attenuation in hundredths of a decibel, from -10000 to 0.
+    pub attenuation: i32,
+    /// Playback frequency in Hz, overriding the sample rate of the file.
+    pub frequency: Option<u32>,
+    /// Pitch offset in semitones.
+    pub pitch: i32,
+    /// Volume as a percentage of the original.
+    pub volume: u32,
+    /// Playback length in milliseconds. None plays the entire sound.
+    pub time: Option<u32>,
+}
+
+impl Default for SoundModifier {
+    fn default() -> Self {
+        Self {
+            pan: 0,
+            attenuation: 0,
+            frequency: None,
+            pitch: 0,
+            volume: 100,
+            time: None,
+        }
+    }
+}
+
+impl SoundModifier {
+    /// Get the rate a sound should be played at, given the sample rate of its file.
+    pub fn playback_rate(&self, sample_rate: u32) -> u32 {
+        let rate = self.frequency.unwrap_or(sample_rate) as f64;
+        let pitch_ratio = 2f64.powf(self.pitch as f64 / 12.0);
+
+        (rate * pitch_ratio).round().max(1.0) as u32
+    }
+
+    /// Get the length of a sound once played with these modifiers.
+    pub fn playback_length(&self, length: f64, sample_rate: u32) -> f64 {
+        let rate_ratio = sample_rate as f64 / self.playback_rate(sample_rate) as f64;
+        let length = length * rate_ratio;
+
+        match self.time {
+            Some(time) => length.min(time as f64 / 1000.0),
+            None => length,
+        }
+    }
+
+    /// Apply the modifiers to loaded audio. This must happen before the audio is resampled,
+    /// since frequency and pitch are applied by reinterpreting the sample rate.
+    pub fn apply(&self, audio: &mut StereoAudio) {
+        if *self == Self::default() {
+            return;
+        }
+
+        // Changing the sample rate without resampling shifts pitch and speed together,
+        // which is how BMS players treat both frequency and pitch.
+        audio.sample_rate = self.playback_rate(audio.sample_rate);
+
+        if let Some(time) = self.time {
+            audio.truncate(time as f64 / 1000.0);
+        }
+
+        // Attenuation is in hundredths of a decibel.
+        let gain = 10f32.powf(self.attenuation as f32 / 2000.0);
+        audio.attenuate(gain * self.volume as f32 / 100.0);
+
+        // DirectSound pan attenuates the opposite channel, again in hundredths of a decibel.
+        if self.pan != 0 {
+            let opposite_gain = 10f32.powf(-(self.pan.abs() as f32) / 2000.0);
+            audio.pan((1.0 - opposite_gain) * self.pan.signum() as f32);
+        }
+    }
+}
+
+/// Sound definitions and commands from #EXWAV and #WAVCMD, keyed by uppercase object ID.
+#[derive(Default)]
+pub struct ExtendedWavDefs {
+    pub paths: HashMap<String, String>,
+    pub modifiers: HashMap<String, SoundModifier>,
+}
+
+/// Split off the first whitespace-separated token of a string.
+fn next_token(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    if line.is_empty() {
+        return None;
+    }
+
+    Some(line.split_once(char::is_whitespace).unwrap_or((line, "")))
+}
+
+impl ExtendedWavDefs {
+    /// Parse #EXWAV and #WAVCMD lines from the source of a BMS file.
+    pub fn parse(source: &str) -> Self {
+        let mut defs = Self::default();
+
+        for line in source.lines() {
+            let line = line.trim();
+            let upper = line.to_ascii_uppercase();
+
+            if let Some(rest) = upper.strip_prefix("#EXWAV") {
+                // Use the original line for the rest, since file names are case sensitive on some systems.
+                let (Some(id), Some(rest)) = (rest.get(..2), line.get(8..)) else {
+                    continue;
+                };
+                defs.parse_exwav(id, rest);
+            } else if let Some(rest) = upper.strip_prefix("#WAVCMD") {
+                defs.parse_wavcmd(rest);
+            }
+        }
+
+        defs
+    }
+
+    /// Parse the definition of an #EXWAV line, in the form `pvf [pan] [volume] [frequency] file`.
+    fn parse_exwav(&mut self, id: &str, rest: &str) {
+        let Some((flags, mut rest)) = next_token(rest) else {
+            return;
+        };
+
+        let modifier = self.modifiers.entry(id.to_string()).or_default();
+
+        // Each flag consumes one value, in the order that the flags are given.
+        for flag in flags.chars() {
+            let Some((value, remainder)) = next_token(rest) else {
+                return;
+            };
+            rest = remainder;
+
+            let Ok(value) = value.parse::<i32>() else {
+                continue;
+            };
+
+            match flag.to_ascii_lowercase() {
+                'p' => modifier.pan = value.clamp(-10000, 10000),
+                'v' => modifier.attenuation = value.clamp(-10000, 0),
+                'f' => modifier.frequency = Some(value.clamp(100, 100000) as u32),
+                _ => (),
+            }
+        }
+
+        let path = rest.trim();
+        if !path.is_empty() {
+            self.paths.insert(id.to_string(), path.to_string());
+        }
+    }
+
+    /// Parse a #WAVCMD line, in the form `[command] [id] [value]`.
+    fn parse_wavcmd(&mut self, rest: &str) {
+        let mut tokens = rest.split_whitespace();
+        let (Some(command), Some(id), Some(value)) = (tokens.next(), tokens.next(), tokens.next())
+        else {
+            return;
+        };
+        let Ok(value) = value.parse::<i32>() else {
+            return;
+        };
+
+        let modifier = self.modifiers.entry(id.to_string()).or_default();
+        match command {
+            // Pitch, where 60 is the original pitch and each step is a semitone.
+            "00" => modifier.pitch = value.clamp(0, 127) - WAVCMD_BASE_PITCH,
+            // Volume as a percentage.
+            "01" => modifier.volume = value.max(0) as u32,
+            // Playback time in milliseconds, where zero plays the whole sound.
+            "02" => modifier.time = (value > 0).then_some(value as u32),
+            _ => (),
+        }
+    }
+
+    /// Get the modifiers of a sound by its object ID.
+    pub fn modifier(&self, id: &str) -> SoundModifier {
+        self.modifiers.get(id).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bms_preview::stereo_audio::StereoSample;
+
+    #[test]
+    fn exwav_flags_take_values_in_their_order() {
+        let defs = ExtendedWavDefs::parse(
+            "#EXWAV01 pvf -5000 -1000 22050 kick.wav\n#exwav0a FP 44100 2000 Snare Hit.wav",
+        );
+
+        let kick = defs.modifier("01");
+        assert_eq!(kick.pan, -5000);
+        assert_eq!(kick.attenuation, -1000);
+        assert_eq!(kick.frequency, Some(22050));
+        assert_eq!(defs.paths["01"], "kick.wav");
+
+        let snare = defs.modifier("0A");
+        assert_eq!(snare.pan, 2000);
+        assert_eq!(snare.attenuation, 0);
+        assert_eq!(snare.frequency, Some(44100));
+        assert_eq!(defs.paths["0A"], "Snare Hit.wav");
+    }
+
+    #[test]
+    fn exwav_values_are_clamped_or_skipped() {
+        let defs = ExtendedWavDefs::parse("#EXWAV01 pv 20000 x a.wav\n#EXWAV02 pv 1000");
+
+        let clamped = defs.modifier("01");
+        assert_eq!(clamped.pan, 10000);
+        assert_eq!(clamped.attenuation, 0);
+        assert_eq!(defs.paths["01"], "a.wav");
+
+        // A definition that runs out of values has no file left to play.
+        assert_eq!(defs.modifier("02").pan, 1000);
+        assert!(!defs.paths.contains_key("02"));
+    }
+
+    #[test]
+    fn wavcmd_commands_set_their_modifier() {
+        let defs = ExtendedWavDefs::parse(
+            "#WAVCMD 00 01 72\n#WAVCMD 01 01 50\n#WAVCMD 02 01 500\n#WAVCMD 02 02 0\n#WAVCMD 03 03 1\n#WAVCMD 00 04",
+        );
+
+        let modifier = defs.modifier("01");
+        assert_eq!(modifier.pitch, 12);
+        assert_eq!(modifier.volume, 50);
+        assert_eq!(modifier.time, Some(500));
+        assert_eq!(modifier.playback_rate(44100), 88200);
+        assert_eq!(modifier.playback_length(1.0, 44100), 0.5);
+
+        assert_eq!(defs.modifier("02").time, None);
+        assert_eq!(defs.modifier("03"), SoundModifier::default());
+        assert_eq!(defs.modifier("04"), SoundModifier::default());
+    }
+
+    /// Apply a modifier to a single full scale sample in both channels.
+    fn apply_to_sample(modifier: SoundModifier) -> (f32, f32) {
+        let mut audio = StereoAudio {
+            buffer: vec![StereoSample {
+                left: 1.0,
+                right: 1.0,
+            }],
+            sample_rate: 44100,
+        };
+        modifier.apply(&mut audio);
+
+        (audio.buffer[0].left, audio.buffer[0].right)
+    }
+
+    #[test]
+    fn full_pan_silences_the_opposite_channel() {
+        // -10000 is -100 dB, which leaves the opposite channel at a hundred thousandth.
+        let (left, right) = apply_to_sample(SoundModifier {
+            pan: 10000,
+            ..Default::default()
+        });
+        assert!(left.abs() < 1e-4, "left gain of {left}");
+        assert_eq!(right, 1.0);
+
+        let (left, right) = apply_to_sample(SoundModifier {
+            pan: -10000,
+            ..Default::default()
+        });
+        assert_eq!(left, 1.0);
+        assert!(right.abs() < 1e-4, "right gain of {right}");
+    }
+
+    #[test]
+    fn attenuation_is_in_hundredths_of_a_decibel() {
+        let (left, right) = apply_to_sample(SoundModifier {
+            attenuation: -2000,
+            volume: 50,
+            ..Default::default()
+        });
+        assert!((left - 0.05).abs() < 1e-6, "left gain of {left}");
+        assert_eq!(left, right);
+    }
+}
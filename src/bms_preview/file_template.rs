@@ -0,0 +1,207 @@
+use itertools::Itertools;
+
+/// The fields that can be used in output file name templates, written as {field}.
+pub const TEMPLATE_FIELDS: [&str; 5] = ["title", "artist", "chart_stem", "md5", "sha256"];
+
+/// Parse an output file name, checking that any template fields in it are known.
+pub fn parse_file_template(s: &str) -> Result<String, String> {
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            return Err("unclosed '{' in file name".to_string());
+        };
+
+        let field = &rest[start + 1..start + length];
+        if !TEMPLATE_FIELDS.contains(&field) {
+            return Err(format!(
+                "unknown field '{{{}}}', expected one of {}",
+                field,
+                TEMPLATE_FIELDS
+                    .iter()
+                    .map(|field| format!("{{{}}}", field))
+                    .join(", ")
+            ));
+        }
+        rest = &rest[start + length + 1..];
+    }
+
+    Ok(s.to_string())
+}
+
+/// A piece of a file name template.
+enum Part<'a> {
+    Text(&'a str),
+    Field(&'a str),
+}
+
+/// Split a file name template into its text and known fields, in order.
+fn template_parts(template: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let field = rest[start + 1..]
+            .find('}')
+            .map(|length| &rest[start + 1..start + 1 + length])
+            .filter(|field| TEMPLATE_FIELDS.contains(field));
+
+        // Braces that aren't around a known field are kept as they are.
+        let Some(field) = field else {
+            parts.push(Part::Text(&rest[..start + 1]));
+            rest = &rest[start + 1..];
+            continue;
+        };
+        parts.push(Part::Text(&rest[..start]));
+        parts.push(Part::Field(field));
+        rest = &rest[start + field.len() + 2..];
+    }
+    parts.push(Part::Text(rest));
+
+    parts
+}
+
+/// Fill in the fields of a file name template with their values.
+/// Values are sanitized, so that a chart's title can't add folders to the path or break it on some platforms.
+/// The template is filled in a single pass, so values that look like fields are left as they are.
+pub fn expand_file_template(template: &str, fields: &[(&str, String)]) -> String {
+    template_parts(template)
+        .into_iter()
+        .map(|part| match part {
+            Part::Text(text) => text.to_string(),
+            Part::Field(field) => fields
+                .iter()
+                .find(|(name, _)| *name == field)
+                .map(|(_, value)| sanitize(value))
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Check if a file name could have been made from a template, with any values for its fields.
+pub fn matches_file_template(template: &str, name: &str) -> bool {
+    // Split the template into the text around its fields, which have to appear in order.
+    let mut texts = vec![String::new()];
+    template_parts(template)
+        .into_iter()
+        .for_each(|part| match part {
+            Part::Text(text) => texts.last_mut().unwrap().push_str(text),
+            Part::Field(_) => texts.push(String::new()),
+        });
+
+    let (first, rest) = texts.split_first().unwrap();
+    let Some((last, middle)) = rest.split_last() else {
+        return name == first;
+    };
+    let Some(mut rest) = name.strip_prefix(first.as_str()) else {
+        return false;
+    };
+
+    // Take the earliest match of each text, which leaves the most room for the rest.
+    for text in middle {
+        let Some(start) = rest.find(text.as_str()) else {
+            return false;
+        };
+        rest = &rest[start + text.len()..];
+    }
+
+    rest.ends_with(last.as_str())
+}
+
+/// Replace the characters that aren't allowed in file names on Windows, which are the strictest.
+fn sanitize(value: &str) -> String {
+    const RESERVED: &str = r#"/\:*?"<>|"#;
+
+    let sanitized: String = value
+        .chars()
+        .map(|c| {
+            if c.is_control() || RESERVED.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+
+    // Names also can't end in a dot or a space.
+    sanitized.trim().trim_end_matches('.').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<(&'static str, String)> {
+        vec![
+            ("title", "Song {md5}".to_string()),
+            ("artist", "AC/DC".to_string()),
+            ("chart_stem", "_7another".to_string()),
+            ("md5", "0123abcd".to_string()),
+        ]
+    }
+
+    #[test]
+    fn parse_accepts_known_fields() {
+        assert!(parse_file_template("preview.ogg").is_ok());
+        assert!(parse_file_template("preview_{chart_stem}.ogg").is_ok());
+        assert!(parse_file_template("{artist} - {title} [{md5}].ogg").is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_bad_fields() {
+        assert!(parse_file_template("preview_{chart}.ogg").is_err());
+        assert!(parse_file_template("preview_{title.ogg").is_err());
+        assert!(parse_file_template("preview_{}.ogg").is_err());
+    }
+
+    #[test]
+    fn expand_fills_in_fields() {
+        assert_eq!(
+            expand_file_template("preview_{chart_stem}.ogg", &fields()),
+            "preview__7another.ogg"
+        );
+        assert_eq!(
+            expand_file_template("{artist} - {chart_stem}{chart_stem}.ogg", &fields()),
+            "AC_DC - _7another_7another.ogg"
+        );
+    }
+
+    #[test]
+    fn expand_leaves_fields_in_values() {
+        assert_eq!(
+            expand_file_template("{title}.ogg", &fields()),
+            "Song {md5}.ogg"
+        );
+    }
+
+    #[test]
+    fn expand_keeps_unknown_braces() {
+        assert_eq!(
+            expand_file_template("{x}{chart_stem}}.ogg", &fields()),
+            "{x}_7another}.ogg"
+        );
+    }
+
+    #[test]
+    fn sanitize_replaces_reserved_characters() {
+        assert_eq!(sanitize(r#"a/b\c:d*e?f"g<h>i|j"#), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("tab\there"), "tab_here");
+        assert_eq!(sanitize(" spaced. "), "spaced");
+        assert_eq!(sanitize("dots..."), "dots");
+    }
+
+    #[test]
+    fn matches_files_made_from_the_template() {
+        let template = "preview_{chart_stem}.ogg";
+        assert!(matches_file_template(template, "preview__7another.ogg"));
+        assert!(matches_file_template(template, "preview_.ogg"));
+        assert!(!matches_file_template(template, "preview.ogg"));
+        assert!(!matches_file_template(template, "preview_normal.flac"));
+
+        assert!(matches_file_template("preview.ogg", "preview.ogg"));
+        assert!(!matches_file_template("preview.ogg", "preview2.ogg"));
+
+        let template = "{artist}-{title}-{md5}.ogg";
+        assert!(matches_file_template(template, "a-b-c.ogg"));
+        assert!(matches_file_template(template, "a-b-c-d.ogg"));
+        assert!(!matches_file_template(template, "a-b.ogg"));
+    }
+}
@@ -0,0 +1,143 @@
+use clap::ValueEnum;
+use realfft::RealFftPlanner;
+
+use crate::bms_preview::stereo_audio::StereoAudio;
+
+/// The sample rate of mixdowns used for finding highlights by audio.
+pub const ANALYSIS_SAMPLE_RATE: u32 = 11025;
+/// The number of samples analysed at a time, about a tenth of a second at the analysis rate.
+const ANALYSIS_FRAME_SIZE: usize = 1024;
+
+/// How the preview window is chosen automatically.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HighlightMode {
+    /// Pick the window with the most notes per second.
+    Density,
+    /// Pick the window with the highest RMS energy of the rendered audio.
+    Energy,
+    /// Pick the window with the most spectral flux (changes in the spectrum) of the rendered audio.
+    Flux,
+}
+
+/// Find the start of the window with the highest total weight of events.
+/// Events are given as (time, weight) pairs, sorted by time.
+/// Returns `None` if no event starts early enough for the window to fit within the song.
+pub fn densest_window(events: &[(f64, f64)], length: f64, song_length: f64) -> Option<f64> {
+    let latest_start = (song_length - length).max(0.0);
+
+    let mut best_start = None;
+    let mut best_weight = f64::MIN;
+    let mut window_weight = 0.0;
+    let mut window_end = 0;
+
+    // The densest window can always be moved to start on an event, so we slide the
+    // window over each event, adding events entering the window and removing those leaving it.
+    for (i, (start, weight)) in events.iter().enumerate() {
+        while window_end < events.len() && events[window_end].0 < start + length {
+            window_weight += events[window_end].1;
+            window_end += 1;
+        }
+
+        if window_weight > best_weight && *start <= latest_start {
+            best_weight = window_weight;
+            best_start = Some(*start);
+        }
+
+        // This event leaves the window before the next one starts it.
+        if i < window_end {
+            window_weight -= weight;
+        }
+    }
+
+    best_start.map(|start| start.clamp(0.0, latest_start))
+}
+
+/// Find the strongest onset within a tolerance of a time, preferring the nearest of equally strong onsets.
+/// Onsets are given as (time, strength) pairs.
+pub fn strongest_onset(onsets: &[(f64, usize)], time: f64, tolerance: f64) -> Option<f64> {
+    onsets
+        .iter()
+        .filter(|(onset, _)| (onset - time).abs() <= tolerance)
+        .max_by(|a, b| {
+            a.1.cmp(&b.1)
+                .then((b.0 - time).abs().total_cmp(&(a.0 - time).abs()))
+        })
+        .map(|(onset, _)| *onset)
+}
+
+/// Get the mean square energy of each frame of mono audio.
+fn frame_energy(samples: &[f32]) -> Vec<f64> {
+    samples
+        .chunks(ANALYSIS_FRAME_SIZE)
+        .map(|frame| {
+            let sum: f64 = frame.iter().map(|sample| (*sample as f64).powi(2)).sum();
+            sum / frame.len() as f64
+        })
+        .collect()
+}
+
+/// Get the spectral flux of each frame of mono audio, which is the total increase in
+/// magnitude across frequency bins compared to the previous frame.
+fn frame_flux(samples: &[f32]) -> Vec<f64> {
+    let fft = RealFftPlanner::<f32>::new().plan_fft_forward(ANALYSIS_FRAME_SIZE);
+    let mut input = fft.make_input_vec();
+    let mut spectrum = fft.make_output_vec();
+    let mut previous = vec![0.0f32; spectrum.len()];
+
+    samples
+        .chunks(ANALYSIS_FRAME_SIZE)
+        .map(|frame| {
+            // Apply a Hann window, padding the last frame with silence.
+            input.iter_mut().enumerate().for_each(|(i, value)| {
+                let window = 0.5
+                    - 0.5
+                        * (2.0 * std::f32::consts::PI * i as f32 / ANALYSIS_FRAME_SIZE as f32)
+                            .cos();
+                *value = frame.get(i).copied().unwrap_or_default() * window;
+            });
+
+            if fft.process(&mut input, &mut spectrum).is_err() {
+                return 0.0;
+            }
+
+            // Only increases in magnitude count, so that the onset of sounds is measured rather than their decay.
+            let mut flux = 0.0;
+            spectrum
+                .iter()
+                .zip(previous.iter_mut())
+                .for_each(|(bin, prev)| {
+                    let magnitude = bin.norm();
+                    flux += (magnitude - *prev).max(0.0) as f64;
+                    *prev = magnitude;
+                });
+
+            flux
+        })
+        .collect()
+}
+
+/// Find the start of the window of a mixdown with the highest energy or spectral flux.
+/// Returns `None` if the mixdown is too short to analyse.
+pub fn loudest_window(mixdown: &StereoAudio, mode: HighlightMode, length: f64) -> Option<f64> {
+    let mono: Vec<f32> = mixdown
+        .buffer
+        .iter()
+        .map(|sample| (sample.left + sample.right) / 2.0)
+        .collect();
+
+    let frames = match mode {
+        HighlightMode::Flux => frame_flux(&mono),
+        _ => frame_energy(&mono),
+    };
+
+    // Treat each frame as an event weighted by its value, so the window with the
+    // highest total is also the one with the highest RMS or flux.
+    let frame_length = ANALYSIS_FRAME_SIZE as f64 / mixdown.sample_rate as f64;
+    let events: Vec<(f64, f64)> = frames
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| (i as f64 * frame_length, value))
+        .collect();
+
+    densest_window(&events, length, mixdown.get_length())
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::bms_preview::atomic_file::temp_path;
+
+/// How files written to an output folder are linked back into song folders.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LinkMode {
+    /// A symbolic link to the file, which works across drives.
+    Symlink,
+    /// A hard link to the file, which needs to be on the same drive as the song folder.
+    Hardlink,
+}
+
+/// Create a link to a target file, replacing anything already at the link's path.
+/// The link is made at a temporary path and renamed into place, so the old file is never missing.
+pub fn link_file(target: &Path, link: &Path, mode: LinkMode) -> io::Result<()> {
+    let temp = temp_path(link);
+
+    let result = match mode {
+        // Symlinks are resolved relative to the link, so point them at an absolute path.
+        LinkMode::Symlink => fs::canonicalize(target).and_then(|target| symlink(&target, &temp)),
+        LinkMode::Hardlink => fs::hard_link(target, &temp),
+    }
+    .and_then(|_| fs::rename(&temp, link));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+
+    result
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
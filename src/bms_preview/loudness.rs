@@ -0,0 +1,266 @@
+use std::f64::consts::PI;
+
+use crate::bms_preview::encoder::{OutputFormat, Tags};
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// The loudness that ReplayGain 2.0 normalizes to (LUFS).
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// The loudness that Opus R128 gains normalize to (LUFS).
+const R128_REFERENCE: f64 = -23.0;
+/// Blocks quieter than this are ignored entirely (LUFS).
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks quieter than this relative to the ungated loudness are ignored (LU).
+const RELATIVE_GATE: f64 = -10.0;
+/// Loudness is measured over 400 ms blocks, overlapping by 75%, so in steps of 100 ms.
+const STEPS_PER_BLOCK: usize = 4;
+const STEPS_PER_SECOND: u32 = 10;
+
+/// A biquad filter, in transposed direct form II.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[1] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[2] * output;
+        output
+    }
+}
+
+/// The K-weighting filter of ITU-R BS.1770, which is a high shelf followed by a high pass.
+/// The coefficients are derived for any sample rate, as in libebur128.
+#[derive(Clone)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10_f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        };
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, input: f32) -> f64 {
+        self.high_pass.process(self.shelf.process(input as f64))
+    }
+}
+
+/// Convert a mean square into loudness (LUFS).
+fn loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Measures the integrated loudness and sample peak of audio, as it's added.
+pub struct LoudnessMeter {
+    filters: Vec<KWeighting>,
+    mono: bool,
+    step_size: usize,
+    /// The sum of squares across channels of the current step, and the number of samples in it.
+    step_energy: f64,
+    step_samples: usize,
+    /// The mean square of each finished step.
+    steps: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    /// Create a meter for audio at a sample rate, measured as it's encoded in mono or stereo.
+    pub fn new(sample_rate: u32, mono: bool) -> Self {
+        let channels = if mono { 1 } else { 2 };
+
+        Self {
+            filters: vec![KWeighting::new(sample_rate); channels],
+            mono,
+            step_size: (sample_rate / STEPS_PER_SECOND).max(1) as usize,
+            step_energy: 0.0,
+            step_samples: 0,
+            steps: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    /// Measure a block of samples.
+    pub fn add(&mut self, samples: &[StereoSample]) {
+        samples.iter().for_each(|sample| {
+            let channels = if self.mono {
+                [(sample.left + sample.right) / 2.0, 0.0]
+            } else {
+                [sample.left, sample.right]
+            };
+
+            self.filters
+                .iter_mut()
+                .zip(channels)
+                .for_each(|(filter, value)| {
+                    self.peak = self.peak.max(value.abs());
+                    let weighted = filter.process(value);
+                    self.step_energy += weighted * weighted;
+                });
+
+            self.step_samples += 1;
+            if self.step_samples == self.step_size {
+                self.steps.push(self.step_energy / self.step_size as f64);
+                self.step_energy = 0.0;
+                self.step_samples = 0;
+            }
+        });
+    }
+
+    /// Get the gated integrated loudness (LUFS), or None if the audio is too short or quiet to measure.
+    pub fn integrated(&self) -> Option<f64> {
+        let blocks: Vec<f64> = self
+            .steps
+            .windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+            .filter(|block| loudness(*block) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+        let relative_gate = loudness(mean(&blocks)) + RELATIVE_GATE;
+        let gated: Vec<f64> = blocks
+            .iter()
+            .copied()
+            .filter(|block| loudness(*block) > relative_gate)
+            .collect();
+
+        Some(loudness(mean(&gated)))
+    }
+
+    /// Get the highest absolute sample value.
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+
+    /// Get ReplayGain 2.0 track tags for the measured audio.
+    /// Opus has its own R128 gain tag, and players ignore ReplayGain tags in it.
+    pub fn replaygain_tags(&self, format: OutputFormat) -> Tags {
+        let Some(loudness) = self.integrated() else {
+            return Tags::new();
+        };
+
+        if format == OutputFormat::Opus {
+            // A Q7.8 fixed point gain, in dB.
+            let gain = ((R128_REFERENCE - loudness) * 256.0).round();
+            let gain = gain.clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            return vec![("R128_TRACK_GAIN".to_string(), gain.to_string())];
+        }
+
+        vec![
+            (
+                "REPLAYGAIN_TRACK_GAIN".to_string(),
+                format!("{:.2} dB", REPLAYGAIN_REFERENCE - loudness),
+            ),
+            (
+                "REPLAYGAIN_TRACK_PEAK".to_string(),
+                format!("{:.6}", self.peak()),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Measure a few seconds of a 1 kHz sine in both channels, with a peak level in dBFS.
+    fn measure_sine(sample_rate: u32, level: f64) -> LoudnessMeter {
+        let amplitude = 10_f64.powf(level / 20.0);
+        let samples: Vec<StereoSample> = (0..sample_rate * 5)
+            .map(|i| {
+                let value =
+                    (amplitude * (2.0 * PI * 1000.0 * i as f64 / sample_rate as f64).sin()) as f32;
+                StereoSample {
+                    left: value,
+                    right: value,
+                }
+            })
+            .collect();
+
+        let mut meter = LoudnessMeter::new(sample_rate, false);
+        samples.chunks(4096).for_each(|block| meter.add(block));
+        meter
+    }
+
+    #[test]
+    fn sine_at_reference_level_measures_reference_loudness() {
+        // EBU Tech 3341 case 1: a stereo 1 kHz sine at -23 dBFS measures -23 LUFS.
+        let loudness = measure_sine(48000, -23.0).integrated().unwrap();
+        assert!((loudness + 23.0).abs() <= 0.1, "measured {loudness} LUFS");
+    }
+
+    #[test]
+    fn loudness_follows_level_at_other_rates() {
+        // EBU Tech 3341 case 2, at the rate that previews are usually rendered at.
+        let loudness = measure_sine(44100, -33.0).integrated().unwrap();
+        assert!((loudness + 33.0).abs() <= 0.1, "measured {loudness} LUFS");
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(48000, false);
+        meter.add(&vec![StereoSample::default(); 48000 * 5]);
+
+        assert_eq!(meter.integrated(), None);
+        assert!(meter.replaygain_tags(OutputFormat::Vorbis).is_empty());
+    }
+
+    #[test]
+    fn replaygain_is_relative_to_its_reference() {
+        let meter = measure_sine(48000, -23.0);
+
+        // ReplayGain normalizes to -18 LUFS, so the sine needs 5 dB more.
+        let tags = meter.replaygain_tags(OutputFormat::Flac);
+        assert_eq!(tags[0].0, "REPLAYGAIN_TRACK_GAIN");
+        let gain: f64 = tags[0].1.strip_suffix(" dB").unwrap().parse().unwrap();
+        assert!((gain - 5.0).abs() <= 0.1, "gain of {gain} dB");
+        assert_eq!(tags[1].0, "REPLAYGAIN_TRACK_PEAK");
+        let peak: f32 = tags[1].1.parse().unwrap();
+        assert!(
+            (peak - 10_f32.powf(-23.0 / 20.0)).abs() < 1e-3,
+            "peak of {peak}"
+        );
+
+        // R128 normalizes to -23 LUFS in steps of 1/256 dB, so the sine needs no gain.
+        let tags = meter.replaygain_tags(OutputFormat::Opus);
+        assert_eq!(tags[0].0, "R128_TRACK_GAIN");
+        let gain: i16 = tags[0].1.parse().unwrap();
+        assert!(gain.abs() <= 26, "gain of {gain}/256 dB");
+    }
+}
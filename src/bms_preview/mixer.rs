@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::FRAC_PI_2;
+use std::path::PathBuf;
+
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::extended_wav::SoundModifier;
+use crate::bms_preview::stereo_audio::{Probe, StereoAudio, StereoSample};
+
+/// The number of samples mixed at a time.
+const MIX_BLOCK_SIZE: usize = 8192;
+
+/// Convert a length of time into samples.
+fn to_samples(time: f64, sample_rate: u32) -> usize {
+    (time * sample_rate as f64).max(0.0) as usize
+}
+
+/// A sound in a chart, along with the times it's played at.
+pub struct Sound {
+    pub path: PathBuf,
+    pub modifier: SoundModifier,
+    pub length: f64,
+    pub times: Vec<f64>,
+}
+
+impl Sound {
+    /// Load the sound at a sample rate, with its modifiers applied.
+    fn load(&self, sample_rate: u32) -> Option<StereoAudio> {
+        let probe = Probe::new(&self.path).ok()?;
+        let mut audio = StereoAudio::load(probe).ok()?;
+
+        // Apply #EXWAV and #WAVCMD modifiers before resampling to the preview's rate.
+        self.modifier.apply(&mut audio);
+        audio.resample(sample_rate as usize).ok()?;
+
+        Some(audio)
+    }
+}
+
+/// Mixes the sounds of a chart between two times, block by block.
+/// Sounds are loaded when they're first played and dropped after they're last played,
+/// so memory use depends on the sounds playing at once rather than the length of the mix.
+pub struct Mixer<'a> {
+    sounds: &'a [Sound],
+    sample_rate: u32,
+    /// Every time a sound is played in the mix, as (sample offset, sound index), sorted by offset.
+    triggers: Vec<(isize, usize)>,
+    next_trigger: usize,
+    /// Triggers whose sound may still be playing.
+    playing: Vec<(isize, usize)>,
+    /// Loaded sounds by index. Sounds that fail to load are kept as None so they aren't retried.
+    loaded: HashMap<usize, Option<StereoAudio>>,
+    /// The offset of the last trigger of each sound.
+    last_trigger: HashMap<usize, isize>,
+    position: usize,
+    length: usize,
+}
+
+impl<'a> Mixer<'a> {
+    /// Set up a mix of the sounds played between the start and end of the song.
+    pub fn new(sounds: &'a [Sound], start: f64, end: f64, sample_rate: u32) -> Self {
+        let offset_samples = |time: f64| ((time - start) * sample_rate as f64).round() as isize;
+
+        // Filter out times that don't fit within the mix.
+        let mut triggers: Vec<(isize, usize)> = sounds
+            .iter()
+            .enumerate()
+            .flat_map(|(index, sound)| {
+                sound
+                    .times
+                    .iter()
+                    .filter(move |time| **time < end && (**time + sound.length) > start)
+                    .map(move |time| (offset_samples(*time), index))
+            })
+            .collect();
+        triggers.sort();
+
+        let mut last_trigger = HashMap::new();
+        triggers.iter().for_each(|(offset, index)| {
+            last_trigger.insert(*index, *offset);
+        });
+
+        Self {
+            sounds,
+            sample_rate,
+            triggers,
+            next_trigger: 0,
+            playing: Vec::new(),
+            loaded: HashMap::new(),
+            last_trigger,
+            position: 0,
+            length: to_samples(end - start, sample_rate),
+        }
+    }
+}
+
+impl Iterator for Mixer<'_> {
+    type Item = StereoAudio;
+
+    /// Mix the next block of audio.
+    fn next(&mut self) -> Option<StereoAudio> {
+        if self.position >= self.length {
+            return None;
+        }
+
+        let block_size = MIX_BLOCK_SIZE.min(self.length - self.position);
+        let block_start = self.position as isize;
+        let block_end = block_start + block_size as isize;
+        let mut block = StereoAudio {
+            buffer: vec![StereoSample::default(); block_size],
+            sample_rate: self.sample_rate,
+        };
+
+        // Start playing the sounds triggered within this block, loading them if needed.
+        while let Some(trigger) = self.triggers.get(self.next_trigger)
+            && trigger.0 < block_end
+        {
+            let (sounds, sample_rate) = (self.sounds, self.sample_rate);
+            self.loaded
+                .entry(trigger.1)
+                .or_insert_with(|| sounds[trigger.1].load(sample_rate));
+            self.playing.push(*trigger);
+            self.next_trigger += 1;
+        }
+
+        // Mix everything that's playing, and keep the sounds that continue past this block.
+        self.playing.retain(|(offset, index)| {
+            let Some(Some(audio)) = self.loaded.get(index) else {
+                return false;
+            };
+            let _ = block.add_samples(audio, offset - block_start);
+
+            offset + audio.buffer.len() as isize > block_end
+        });
+
+        // Drop the sounds that aren't playing and won't be played again.
+        let playing: HashSet<usize> = self.playing.iter().map(|(_, index)| *index).collect();
+        self.loaded.retain(|index, _| {
+            playing.contains(index)
+                || self
+                    .last_trigger
+                    .get(index)
+                    .is_some_and(|last| *last >= block_end)
+        });
+
+        self.position += block_size;
+        Some(block)
+    }
+}
+
+/// The windows of a song that make up a preview, and how they're joined together.
+pub struct PreviewPlan {
+    /// The windows in seconds, in the order they're played.
+    pub windows: Vec<(f64, f64)>,
+    /// The duration of the crossfade between windows.
+    pub crossfade: f64,
+    /// The duration of the crossfade from the end of the preview back into its start, if it loops.
+    pub loop_crossfade: Option<f64>,
+}
+
+impl PreviewPlan {
+    /// Get the length in samples of each window, and of its crossfade into the next window.
+    /// Crossfades can't be longer than either window, or overlap the crossfade at the start of a window.
+    fn lengths(&self, sample_rate: u32) -> Vec<(usize, usize)> {
+        let crossfade = to_samples(self.crossfade, sample_rate);
+        let lengths: Vec<usize> = self
+            .windows
+            .iter()
+            .map(|(start, end)| to_samples(end - start, sample_rate))
+            .collect();
+
+        let mut crossfade_in = 0;
+        lengths
+            .iter()
+            .enumerate()
+            .map(|(index, length)| {
+                let crossfade_out = match lengths.get(index + 1) {
+                    Some(next) => crossfade.min(length - crossfade_in).min(*next),
+                    None => 0,
+                };
+                crossfade_in = crossfade_out;
+
+                (*length, crossfade_out)
+            })
+            .collect()
+    }
+
+    /// Get the length of the preview in samples, once its windows are joined.
+    pub fn length(&self, sample_rate: u32) -> usize {
+        self.lengths(sample_rate)
+            .iter()
+            .map(|(length, crossfade)| length - crossfade)
+            .sum()
+    }
+
+    /// Mix the preview block by block, passing each block to a closure in order.
+    /// Only the samples being crossfaded are held back, so memory use doesn't depend on the preview's length.
+    pub fn mix(
+        &self,
+        sounds: &[Sound],
+        sample_rate: u32,
+        mut emit: impl FnMut(StereoAudio) -> Result<(), AudioError>,
+    ) -> Result<(), AudioError> {
+        let lengths = self.lengths(sample_rate);
+
+        // Looping previews have the audio past their end crossfaded into their start, so it's mixed first.
+        let tail: Vec<StereoSample> = match (self.loop_crossfade, self.windows.last()) {
+            (Some(crossfade), Some((_, end))) => {
+                Mixer::new(sounds, *end, end + crossfade, sample_rate)
+                    .flat_map(|block| block.buffer)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let loop_samples = tail.len().min(self.length(sample_rate));
+
+        let mut position = 0;
+        // The end of the last window, which is crossfaded into the start of the next.
+        let mut held: Vec<StereoSample> = Vec::new();
+        for ((start, end), (length, crossfade_out)) in self.windows.iter().zip(lengths) {
+            let crossfade_in = held.len();
+            let mut next_held = Vec::with_capacity(crossfade_out);
+            let mut window_position = 0;
+
+            for block in Mixer::new(sounds, *start, *end, sample_rate) {
+                let mut output = Vec::with_capacity(block.buffer.len());
+                for mut sample in block.buffer {
+                    let index = window_position;
+                    window_position += 1;
+
+                    if index >= length - crossfade_out {
+                        next_held.push(sample);
+                        continue;
+                    }
+
+                    // Use an equal power crossfade, so that the volume doesn't dip in the middle.
+                    if index < crossfade_in {
+                        let ratio = index as f32 / crossfade_in as f32 * FRAC_PI_2;
+                        sample = held[index] * ratio.cos() + sample * ratio.sin();
+                    }
+                    if position + output.len() < loop_samples {
+                        let loop_index = position + output.len();
+                        let ratio = loop_index as f32 / loop_samples as f32 * FRAC_PI_2;
+                        sample = sample * ratio.sin() + tail[loop_index] * ratio.cos();
+                    }
+
+                    output.push(sample);
+                }
+
+                position += output.len();
+                emit(StereoAudio {
+                    buffer: output,
+                    sample_rate,
+                })?;
+            }
+
+            held = next_held;
+        }
+
+        Ok(())
+    }
+}
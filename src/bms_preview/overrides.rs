@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::bms_preview::Args;
+use crate::bms_preview::errors::OverrideError;
+
+/// Preview settings for a single song, read from a file in its folder.
+/// Anything that isn't set falls back on the arguments of the batch.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SongOverrides {
+    /// The file name of the chart to render, instead of the first one found.
+    pub chart: Option<String>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub start_p: Option<f64>,
+    pub end_p: Option<f64>,
+    pub start_measure: Option<f64>,
+    pub end_measure: Option<f64>,
+    pub duration: Option<f64>,
+    pub measures: Option<f64>,
+    pub fade_in: Option<f64>,
+    pub fade_out: Option<f64>,
+    pub volume: Option<f32>,
+}
+
+impl SongOverrides {
+    /// Read the override file of a song, if it exists, checking that the chart it chooses exists.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>, OverrideError> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let source = fs::read_to_string(path)?;
+        let overrides: Self = toml::from_str(&source)?;
+
+        // Otherwise every chart in the folder would be quietly left out.
+        if let Some(chart) = &overrides.chart
+            && !path.with_file_name(chart).is_file()
+        {
+            return Err(OverrideError::MissingChart(chart.clone()));
+        }
+
+        Ok(Some(overrides))
+    }
+
+    /// Check if the overrides set any part of the preview window.
+    fn sets_window(&self) -> bool {
+        [
+            self.start,
+            self.end,
+            self.start_p,
+            self.end_p,
+            self.start_measure,
+            self.end_measure,
+            self.duration,
+            self.measures,
+        ]
+        .iter()
+        .any(Option::is_some)
+    }
+
+    /// Apply the overrides on top of the arguments of the batch.
+    pub fn apply(&self, args: &Args) -> Args {
+        let mut args = args.clone();
+
+        // The window is replaced as a whole, so that it can't contradict the batch's window.
+        if self.sets_window() {
+            args.settings.start = self.start;
+            args.settings.end = self.end;
+            args.settings.start_p = self.start_p;
+            args.settings.end_p = self.end_p;
+            args.settings.start_measure = self.start_measure;
+            args.settings.end_measure = self.end_measure;
+            args.settings.duration = self.duration;
+            args.settings.measures = self.measures;
+        }
+
+        args.settings.fade_in = self.fade_in.unwrap_or(args.settings.fade_in);
+        args.settings.fade_out = self.fade_out.unwrap_or(args.settings.fade_out);
+        args.settings.volume = self.volume.unwrap_or(args.settings.volume);
+
+        args
+    }
+
+    /// Describe the overrides that are set, for reporting in the run output.
+    pub fn describe(&self) -> String {
+        let mut set: Vec<String> = Vec::new();
+        let mut describe = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                set.push(format!("{}={}", name, value));
+            }
+        };
+
+        describe("chart", self.chart.clone());
+        describe("start", self.start.map(|v| v.to_string()));
+        describe("end", self.end.map(|v| v.to_string()));
+        describe("start_p", self.start_p.map(|v| v.to_string()));
+        describe("end_p", self.end_p.map(|v| v.to_string()));
+        describe("start_measure", self.start_measure.map(|v| v.to_string()));
+        describe("end_measure", self.end_measure.map(|v| v.to_string()));
+        describe("duration", self.duration.map(|v| v.to_string()));
+        describe("measures", self.measures.map(|v| v.to_string()));
+        describe("fade_in", self.fade_in.map(|v| v.to_string()));
+        describe("fade_out", self.fade_out.map(|v| v.to_string()));
+        describe("volume", self.volume.map(|v| v.to_string()));
+
+        set.join(", ")
+    }
+}
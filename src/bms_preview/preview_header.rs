@@ -0,0 +1,387 @@
+use std::fs;
+use std::path::Path;
+
+use encoding_rs::{Encoding, UTF_8};
+use serde_json::Value;
+
+use crate::bms_preview::atomic_file::write_atomic;
+use crate::bms_preview::errors::HeaderError;
+use crate::bms_preview::renderer::backup_path;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Point the preview of a chart at a file, keeping a backup of the original chart.
+/// BMS charts get a #PREVIEW header, and BMSON charts get a preview_music field.
+pub fn write_preview_header(
+    chart_path: &Path,
+    encoding: &'static Encoding,
+    preview_file: &str,
+) -> Result<(), HeaderError> {
+    let bytes = fs::read(chart_path)?;
+    let is_bmson = chart_path.extension().is_some_and(|ext| ext == "bmson");
+
+    let updated = if is_bmson {
+        set_bmson_preview(&bytes, encoding, preview_file)?
+    } else {
+        set_bms_preview(&bytes, encoding, preview_file)?
+    };
+
+    // Don't touch the chart at all if it already points at the preview.
+    let Some(updated) = updated else {
+        return Ok(());
+    };
+
+    // Only back up the chart the first time, so the backup is always the author's original.
+    let backup = backup_path(chart_path);
+    if !backup.exists() {
+        fs::copy(chart_path, &backup)?;
+    }
+    write_atomic(chart_path, updated)?;
+
+    Ok(())
+}
+
+/// Insert or update the #PREVIEW header of a BMS chart, keeping its encoding and line endings.
+/// Returns None if the chart doesn't need to change.
+fn set_bms_preview(
+    bytes: &[u8],
+    encoding: &'static Encoding,
+    preview_file: &str,
+) -> Result<Option<Vec<u8>>, HeaderError> {
+    let (source, has_bom) = decode_chart(bytes, encoding)?;
+
+    let line_ending = if source.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let header = format!("#PREVIEW {}", preview_file);
+
+    let mut lines: Vec<&str> = source.split(line_ending).collect();
+    let is_header = |line: &&str, name: &str| {
+        let line = line.trim_start().to_ascii_uppercase();
+        line.strip_prefix(name)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    };
+
+    if let Some(index) = lines.iter().position(|line| is_header(line, "#PREVIEW")) {
+        if lines[index].trim() == header {
+            return Ok(None);
+        }
+        lines[index] = &header;
+    } else {
+        // Keep the header with the rest of the song information, after the title if there is one.
+        let position = lines
+            .iter()
+            .position(|line| is_header(line, "#TITLE"))
+            .map_or(0, |title| title + 1);
+        lines.insert(position, &header);
+    }
+
+    Ok(Some(encode_chart(
+        &lines.join(line_ending),
+        encoding,
+        has_bom,
+    )?))
+}
+
+/// Set the preview_music field of a BMSON chart, keeping its encoding and formatting.
+/// Only the value of the field is changed, or the field is added to the start of the info.
+/// Returns None if the chart doesn't need to change.
+fn set_bmson_preview(
+    bytes: &[u8],
+    encoding: &'static Encoding,
+    preview_file: &str,
+) -> Result<Option<Vec<u8>>, HeaderError> {
+    let (source, has_bom) = decode_chart(bytes, encoding)?;
+
+    // Parse the chart first, so that the JSON is known to be valid while it's scanned.
+    let bmson: Value = serde_json::from_str(&source)?;
+    let info = bmson
+        .get("info")
+        .and_then(Value::as_object)
+        .ok_or(HeaderError::MissingBmsonInfo())?;
+    if info.get("preview_music").and_then(Value::as_str) == Some(preview_file) {
+        return Ok(None);
+    }
+
+    let info_start = object_members(&source, 0)
+        .into_iter()
+        .find(|member| member.key == "info")
+        .ok_or(HeaderError::MissingBmsonInfo())?
+        .value_start;
+    let members = object_members(&source, info_start);
+    let value = serde_json::to_string(preview_file)?;
+
+    let mut text = source.clone();
+    if let Some(member) = members.iter().find(|member| member.key == "preview_music") {
+        text.replace_range(member.value_start..member.value_end, &value);
+    } else if let Some(first) = members.first() {
+        // Indent the new field like the one after it.
+        let indent = &source[info_start + 1..first.key_start];
+        text.insert_str(
+            first.key_start,
+            &format!("\"preview_music\": {},{}", value, indent),
+        );
+    } else {
+        text.insert_str(info_start + 1, &format!("\"preview_music\": {}", value));
+    }
+
+    Ok(Some(encode_chart(&text, encoding, has_bom)?))
+}
+
+/// Decode a chart, and check if it starts with a UTF-8 byte order mark.
+fn decode_chart(bytes: &[u8], encoding: &'static Encoding) -> Result<(String, bool), HeaderError> {
+    let has_bom = bytes.starts_with(UTF8_BOM);
+    let (source, _, failure) = encoding.decode(bytes);
+    if failure {
+        return Err(HeaderError::Undecodable());
+    }
+
+    Ok((source.into_owned(), has_bom))
+}
+
+/// Encode a chart back into the encoding it was read in.
+fn encode_chart(
+    text: &str,
+    encoding: &'static Encoding,
+    has_bom: bool,
+) -> Result<Vec<u8>, HeaderError> {
+    let (encoded, _, unmappable) = encoding.encode(text);
+    if unmappable {
+        return Err(HeaderError::Unencodable());
+    }
+
+    // The decoder strips byte order marks, so we put it back.
+    let mut output = Vec::with_capacity(encoded.len() + UTF8_BOM.len());
+    if has_bom && encoding == UTF_8 {
+        output.extend_from_slice(UTF8_BOM);
+    }
+    output.extend_from_slice(&encoded);
+
+    Ok(output)
+}
+
+/// A member of a JSON object, with the positions of its key and value in the text.
+struct Member {
+    key: String,
+    key_start: usize,
+    value_start: usize,
+    value_end: usize,
+}
+
+/// Find the members of the JSON object that starts at or after a position in valid JSON text.
+fn object_members(text: &str, start: usize) -> Vec<Member> {
+    let bytes = text.as_bytes();
+    let mut members = Vec::new();
+    let Some(open) = text[start..].find('{') else {
+        return members;
+    };
+
+    let mut position = start + open + 1;
+    loop {
+        position = skip_whitespace(bytes, position);
+        if bytes.get(position) != Some(&b'"') {
+            return members;
+        }
+
+        let key_start = position;
+        let key_end = skip_value(bytes, key_start);
+        let key = serde_json::from_str(&text[key_start..key_end]).unwrap_or_default();
+
+        // Skip the colon between the key and the value.
+        let value_start = skip_whitespace(bytes, skip_whitespace(bytes, key_end) + 1);
+        let value_end = skip_value(bytes, value_start);
+        members.push(Member {
+            key,
+            key_start,
+            value_start,
+            value_end,
+        });
+
+        position = skip_whitespace(bytes, value_end);
+        if bytes.get(position) != Some(&b',') {
+            return members;
+        }
+        position += 1;
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], position: usize) -> usize {
+    bytes
+        .iter()
+        .skip(position)
+        .position(|byte| !byte.is_ascii_whitespace())
+        .map_or(bytes.len(), |length| position + length)
+}
+
+/// Get the end of the JSON value that starts at a position.
+fn skip_value(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (position, byte) in bytes.iter().enumerate().skip(start) {
+        if in_string {
+            match (escaped, *byte) {
+                (true, _) => escaped = false,
+                (false, b'\\') => escaped = true,
+                (false, b'"') => {
+                    in_string = false;
+                    if depth == 0 {
+                        return position + 1;
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        match *byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    return position + 1;
+                }
+            }
+            // Numbers and literals end at the next separator.
+            b',' | b'}' | b']' => return position,
+            byte if byte.is_ascii_whitespace() && depth == 0 => return position,
+            _ => {}
+        }
+    }
+
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::SHIFT_JIS;
+
+    use super::*;
+
+    fn set_bms(source: &str, preview_file: &str) -> Option<String> {
+        let output = set_bms_preview(source.as_bytes(), UTF_8, preview_file).unwrap();
+        output.map(|output| String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn bms_preview_is_added_after_title() {
+        let source = "*---HEADER\n#PLAYER 1\n#TITLE Song\n#ARTIST Someone\n";
+        assert_eq!(
+            set_bms(source, "preview.ogg").unwrap(),
+            "*---HEADER\n#PLAYER 1\n#TITLE Song\n#PREVIEW preview.ogg\n#ARTIST Someone\n"
+        );
+
+        // Charts without a title get the header at the top.
+        assert_eq!(
+            set_bms("#PLAYER 1\n", "preview.ogg").unwrap(),
+            "#PREVIEW preview.ogg\n#PLAYER 1\n"
+        );
+    }
+
+    #[test]
+    fn bms_preview_is_replaced_with_line_endings_kept() {
+        let source = "#TITLE Song\r\n#preview old.wav\r\n#TITLE2 is not the title\r\n";
+        assert_eq!(
+            set_bms(source, "new.ogg").unwrap(),
+            "#TITLE Song\r\n#PREVIEW new.ogg\r\n#TITLE2 is not the title\r\n"
+        );
+    }
+
+    #[test]
+    fn bms_unchanged_preview_is_left_alone() {
+        assert_eq!(
+            set_bms("#TITLE Song\n#PREVIEW preview.ogg\n", "preview.ogg"),
+            None
+        );
+    }
+
+    #[test]
+    fn bms_encoding_is_kept() {
+        let (source, _, _) = SHIFT_JIS.encode("#TITLE 曲名\r\n#ARTIST 作曲者\r\n");
+        let output = set_bms_preview(&source, SHIFT_JIS, "プレビュー.ogg")
+            .unwrap()
+            .unwrap();
+        let (expected, _, _) =
+            SHIFT_JIS.encode("#TITLE 曲名\r\n#PREVIEW プレビュー.ogg\r\n#ARTIST 作曲者\r\n");
+        assert_eq!(output, expected.into_owned());
+
+        let source = b"\xEF\xBB\xBF#TITLE Song\n";
+        let output = set_bms_preview(source, UTF_8, "preview.ogg")
+            .unwrap()
+            .unwrap();
+        assert_eq!(output, b"\xEF\xBB\xBF#TITLE Song\n#PREVIEW preview.ogg\n");
+    }
+
+    #[test]
+    fn bms_unencodable_preview_is_rejected() {
+        let (source, _, _) = SHIFT_JIS.encode("#TITLE 曲名\n");
+        assert!(matches!(
+            set_bms_preview(&source, SHIFT_JIS, "\u{1F3B5}.ogg"),
+            Err(HeaderError::Unencodable())
+        ));
+        assert!(matches!(
+            set_bms_preview(b"#TITLE \xFF\n", UTF_8, "preview.ogg"),
+            Err(HeaderError::Undecodable())
+        ));
+    }
+
+    fn set_preview(source: &str, preview_file: &str) -> String {
+        let output = set_bmson_preview(source.as_bytes(), UTF_8, preview_file).unwrap();
+        String::from_utf8(output.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn bmson_preview_value_is_replaced() {
+        let source = "{\r\n  \"version\": \"1.0.0\",\r\n  \"info\": {\r\n    \"title\": \"a \\\"b\\\" {c}\",\r\n    \"preview_music\": \"old.ogg\",\r\n    \"level\": 12\r\n  },\r\n  \"lines\": []\r\n}";
+        assert_eq!(
+            set_preview(source, "new.ogg"),
+            source.replace("old.ogg", "new.ogg")
+        );
+    }
+
+    #[test]
+    fn bmson_preview_is_added_to_info() {
+        let source = "{\"info\":{\"title\":\"a\",\"level\":12},\"preview_music\":\"x\"}";
+        assert_eq!(
+            set_preview(source, "new.ogg"),
+            "{\"info\":{\"preview_music\": \"new.ogg\",\"title\":\"a\",\"level\":12},\"preview_music\":\"x\"}"
+        );
+
+        let source = "{\n  \"info\": {\n    \"title\": \"a\"\n  }\n}";
+        assert_eq!(
+            set_preview(source, "new.ogg"),
+            "{\n  \"info\": {\n    \"preview_music\": \"new.ogg\",\n    \"title\": \"a\"\n  }\n}"
+        );
+
+        assert_eq!(
+            set_preview("{\"info\": {}}", "new.ogg"),
+            "{\"info\": {\"preview_music\": \"new.ogg\"}}"
+        );
+    }
+
+    #[test]
+    fn bmson_byte_order_mark_is_kept() {
+        let source = b"\xEF\xBB\xBF{\"info\": {\"preview_music\": \"old.ogg\"}}";
+        let output = set_bmson_preview(source, UTF_8, "new.ogg")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            output,
+            b"\xEF\xBB\xBF{\"info\": {\"preview_music\": \"new.ogg\"}}"
+        );
+    }
+
+    #[test]
+    fn bmson_unchanged_preview_is_left_alone() {
+        let source = b"{\"info\": {\"preview_music\": \"preview.ogg\"}}";
+        assert!(
+            set_bmson_preview(source, UTF_8, "preview.ogg")
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -1,131 +1,273 @@
-use crate::bms_preview::Args;
+use crate::bms_preview::encoder::{
+    Encoder, EncoderSettings, OutputFormat, SharedWriter, Tags, VENDOR, create_encoder,
+    create_output_encoder,
+};
 use crate::bms_preview::errors::*;
+use crate::bms_preview::extended_wav::{ExtendedWavDefs, SoundModifier};
+use crate::bms_preview::file_template::{expand_file_template, matches_file_template};
+use crate::bms_preview::highlight::{
+    ANALYSIS_SAMPLE_RATE, HighlightMode, densest_window, loudest_window, strongest_onset,
+};
+use crate::bms_preview::link::link_file;
+use crate::bms_preview::loudness::LoudnessMeter;
+use crate::bms_preview::mixer::{Mixer, PreviewPlan, Sound};
+use crate::bms_preview::preview_header::write_preview_header;
+use crate::bms_preview::sidecar::Sidecars;
 use crate::bms_preview::stereo_audio::Probe;
 use crate::bms_preview::stereo_audio::StereoAudio;
+use crate::bms_preview::stereo_audio::{VALID_AUDIO, get_audio_fuzzy};
+use crate::bms_preview::timeline::{SnapMode, Timeline};
+use crate::bms_preview::window::{WindowSpec, fit_window};
+use crate::bms_preview::{Args, PreviewSettings};
 
 use bms_rs::bms::model::Bms;
-use bms_rs::bms::prelude::ObjTime;
-use bms_rs::bms::prelude::{BpmChangeObj, KeyLayoutBeat};
-use bms_rs::bms::{Decimal, default_config, parse_bms};
+use bms_rs::bms::prelude::{Key, KeyLayoutBeat, KeyLayoutMapper, PlayerSide, WavObj};
+use bms_rs::bms::{default_config, parse_bms};
 use bms_rs::bmson::parse_bmson;
 use chardetng::EncodingDetector;
+use clap::ValueEnum;
+use encoding_rs::Encoding;
 use itertools::Itertools;
+use md5::Md5;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fs;
-use std::ops::Mul;
+use std::io::{Cursor, Write};
 use std::path::Path;
 use std::path::PathBuf;
 
+/// Get the path that a file is backed up to before it's replaced.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_os_string();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Describe the windows of a song that were rendered, in seconds.
+fn describe_windows(windows: &[(f64, f64)]) -> String {
+    windows
+        .iter()
+        .map(|(start, end)| format!("{:.3}-{:.3}", start, end))
+        .join(",")
+}
+
+/// Get the name of the stem of a player lane, such as player1_key1 or player2_scratch.
+fn stem_name(side: PlayerSide, key: Key) -> String {
+    let side = match side {
+        PlayerSide::Player1 => "player1",
+        PlayerSide::Player2 => "player2",
+    };
+    let key = match key {
+        Key::Key(number) => format!("key{}", number),
+        Key::Scratch(1) => "scratch".to_string(),
+        Key::Scratch(number) => format!("scratch{}", number),
+        Key::FootPedal => "foot_pedal".to_string(),
+        Key::FreeZone => "free_zone".to_string(),
+    };
+
+    format!("{}_{}", side, key)
+}
+
+/// Audio extensions that beatoraja accepts for preview files.
+const PREVIEW_EXTENSIONS: [&str; 4] = ["wav", "ogg", "mp3", "flac"];
+
+/// What to do when a song folder already has other preview files.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExistingPreviews {
+    /// Don't generate a preview.
+    Skip,
+    /// Generate a preview, moving the existing ones to backups.
+    Replace,
+    /// Generate a preview alongside the existing ones.
+    KeepBoth,
+}
+
+/// The result of processing a chart.
+pub enum Outcome {
+    /// A preview was written.
+    Rendered,
+    /// No preview was written, for the given reason.
+    Skipped(String),
+}
+
 pub struct Renderer {
     bms: Bms,
     base_path: PathBuf,
+    chart_path: PathBuf,
+    encoding: &'static Encoding,
+    extended_wav: ExtendedWavDefs,
+    timeline: Timeline,
+    /// The MD5 hash of the chart file in hex, which is how players identify charts.
+    md5: String,
+    /// The SHA-256 hash of the chart file, in hex.
+    sha256: String,
 }
 
 impl Renderer {
-    // referenced from https://github.com/approvers/bms-bounce/blob/master/bms-rs-wasm/src/lib.rs
-    /// Get the timings of sounds in a BMS file along with their paths.
-    fn get_wav_timings(&self) -> HashMap<PathBuf, Vec<f64>> {
-        let bpm_changes = &self.bms.bpm.bpm_changes;
-        let section_len_changes = &self.bms.section_len.section_len_changes;
-
-        const DEFAULT_BPM: f64 = 130.0;
-        let default_bpm_dec = Decimal::from(DEFAULT_BPM);
-        let mut current_bpm: f64 = self
-            .bms
-            .bpm
-            .bpm
-            .clone()
-            .unwrap_or(default_bpm_dec)
-            .try_into()
-            .unwrap_or(DEFAULT_BPM);
-        let mut current_section_time = 0.0;
-        let mut next_section_time = 0.0;
-        let mut previous_section = 0;
-
-        let first_bpm_change = bpm_changes.range(..ObjTime::new(2, 0, 4).unwrap()).next();
-        if let Some((_, BpmChangeObj { bpm: first_bpm, .. })) = first_bpm_change {
-            current_bpm = first_bpm.clone().try_into().unwrap_or(DEFAULT_BPM);
-        }
-
-        let mut timings: HashMap<PathBuf, Vec<f64>> = HashMap::new();
-
-        self.bms
-            .wav
-            .notes
-            .bgms::<KeyLayoutBeat>()
-            .sorted_by(|a, b| a.offset.cmp(&b.offset))
-            .for_each(|note| {
-                let track = note.offset.track();
-                let numerator = note.offset.numerator();
-                let denominator = note.offset.denominator_u64();
-
-                let one = Decimal::from(1);
-                let current_section_len = section_len_changes
-                    .get(&note.offset.track())
-                    .map_or(one, |obj| obj.length.clone());
-                let section_beats: f64 = current_section_len.mul(4).try_into().unwrap();
-                let seconds_per_beat = 60.0 / current_bpm;
-                let section_seconds = section_beats * seconds_per_beat;
-
-                if previous_section < track.0 {
-                    current_section_time = next_section_time;
-                    previous_section = track.0;
-                }
+    /// Get the path and modifiers of the sound that a note plays.
+    fn get_note_sound(&self, note: &WavObj) -> Option<(PathBuf, SoundModifier)> {
+        // Sounds defined by #EXWAV may not be known to the parser, so fall back on our own definitions.
+        let id = note.wav_id.to_string().to_ascii_uppercase();
+        let path = if let Some(name) = self.bms.wav.wav_files.get(&note.wav_id) {
+            self.base_path.join(name)
+        } else if let Some(name) = self.extended_wav.paths.get(&id) {
+            self.base_path.join(name)
+        } else {
+            return None;
+        };
 
-                let obj_offset_seconds = section_seconds * numerator as f64 / denominator as f64;
-                let obj_start_seconds = current_section_time + obj_offset_seconds;
-                next_section_time = current_section_time + section_seconds;
+        // The same file played with different modifiers is treated as a different sound.
+        Some((path, self.extended_wav.modifier(&id)))
+    }
 
-                let first_bpm_change = bpm_changes.range(note.offset..).next();
-                if let Some((_, BpmChangeObj { bpm: first_bpm, .. })) = first_bpm_change {
-                    current_bpm = first_bpm.clone().try_into().unwrap_or(current_bpm);
-                }
+    /// Get the folder that this chart's output files are written to.
+    /// With an output folder set, this mirrors the song folder's path within the songs folder, so the song folder is untouched.
+    fn output_folder(&self, args: &Args) -> PathBuf {
+        let Some(output_dir) = &args.output_dir else {
+            return self.base_path.clone();
+        };
+
+        let songs_folder = Path::new(args.songs_folder.as_deref().unwrap_or_default());
+        let relative = match self.base_path.strip_prefix(songs_folder) {
+            Ok(relative) => relative,
+            Err(_) => Path::new(self.base_path.file_name().unwrap_or_default()),
+        };
+
+        Path::new(output_dir).join(relative)
+    }
+
+    /// Get the name of an output file from a template, filled in with this chart's fields.
+    fn file_name(&self, template: &str) -> String {
+        let info = &self.bms.music_info;
+        let chart_stem = self.chart_path.file_stem().unwrap_or_default();
+
+        expand_file_template(
+            template,
+            &[
+                ("title", info.title.clone().unwrap_or_default()),
+                ("artist", info.artist.clone().unwrap_or_default()),
+                ("chart_stem", chart_stem.to_string_lossy().into_owned()),
+                ("md5", self.md5.clone()),
+                ("sha256", self.sha256.clone()),
+            ],
+        )
+    }
+
+    /// Get the timings of the sounds played by notes along with their paths and modifiers.
+    fn get_wav_timings<'a>(
+        &self,
+        notes: impl Iterator<Item = &'a WavObj>,
+    ) -> HashMap<(PathBuf, SoundModifier), Vec<f64>> {
+        let mut timings: HashMap<(PathBuf, SoundModifier), Vec<f64>> = HashMap::new();
+
+        notes.for_each(|note| {
+            let obj_start_seconds = self.timeline.seconds(note.offset);
 
-                let Some(name) = self.bms.wav.wav_files.get(&note.wav_id) else {
+            let Some(key) = self.get_note_sound(note) else {
+                return;
+            };
+            if let Some(timing_vec) = timings.get_mut(&key) {
+                timing_vec.push(obj_start_seconds);
+            } else {
+                timings.insert(key, vec![obj_start_seconds]);
+            }
+        });
+
+        timings
+    }
+
+    /// Get the timings of sounds grouped into stems, by the name of the stem.
+    /// BGM notes make up one stem, and the notes of each player lane make up the others.
+    fn get_stem_timings(&self) -> HashMap<String, HashMap<(PathBuf, SoundModifier), Vec<f64>>> {
+        let mut stems: HashMap<String, HashMap<(PathBuf, SoundModifier), Vec<f64>>> =
+            HashMap::new();
+
+        let notes = &self.bms.wav.notes;
+        notes
+            .playables::<KeyLayoutBeat>()
+            .chain(notes.bgms::<KeyLayoutBeat>())
+            .for_each(|note| {
+                let Some(key) = self.get_note_sound(note) else {
                     return;
                 };
 
-                let path = self.base_path.join(name);
+                let stem = match note.channel_id.try_into_map::<KeyLayoutBeat>() {
+                    Some(lane) => stem_name(lane.side(), lane.key()),
+                    None => "bgm".to_string(),
+                };
 
-                if let Some(timing_vec) = timings.get_mut(&path) {
-                    timing_vec.push(obj_start_seconds);
-                } else {
-                    timings.insert(path.clone(), vec![obj_start_seconds]);
-                }
+                stems
+                    .entry(stem)
+                    .or_default()
+                    .entry(key)
+                    .or_default()
+                    .push(self.timeline.seconds(note.offset));
             });
 
-        timings
+        stems
     }
 
-    /// Process a BMS file, outputting an audio preview file.
-    pub fn process_bms_file(&self, args: &Args) -> Result<(), AudioError> {
-        let preview_path = self.base_path.join(&args.preview_file);
-        // If the BMS file has a preview set, then that'll be played by default, regardless of if we generate a preview.
-        if let Some(_) = self.bms.music_info.preview_music {
-            return Ok(());
-        }
-        // If we don't allow overwrites, and a preview already exists by the same name, we'll skip.
-        if !args.overwrite && preview_path.exists() {
-            return Ok(());
-        }
+    /// Get the times of notes weighted for density, sorted by time.
+    /// Playable notes have a weight of one, and BGM notes have the passed weight.
+    fn get_density_events(&self, bgm_weight: f64) -> Vec<(f64, f64)> {
+        let notes = &self.bms.wav.notes;
+        let playables = notes
+            .playables::<KeyLayoutBeat>()
+            .map(|note| (self.timeline.seconds(note.offset), 1.0));
+
+        // Skip BGM entirely when it isn't weighted.
+        let bgms = notes
+            .bgms::<KeyLayoutBeat>()
+            .filter(|_| bgm_weight > 0.0)
+            .map(|note| (self.timeline.seconds(note.offset), bgm_weight));
+
+        playables
+            .chain(bgms)
+            .sorted_by(|a, b| a.0.total_cmp(&b.0))
+            .collect()
+    }
 
-        let mut sample_rate = args.sample_rate;
+    /// Get the times that notes are triggered at along with the number of notes triggered together,
+    /// which is used as the strength of the onset. Sorted by time.
+    fn get_onsets(&self) -> Vec<(f64, usize)> {
+        // Notes closer together than this are treated as triggered together.
+        const ONSET_RESOLUTION: f64 = 0.001;
+
+        let notes = &self.bms.wav.notes;
+        let times = notes
+            .playables::<KeyLayoutBeat>()
+            .chain(notes.bgms::<KeyLayoutBeat>())
+            .map(|note| self.timeline.seconds(note.offset))
+            .sorted_by(|a, b| a.total_cmp(b));
+
+        let mut onsets: Vec<(f64, usize)> = Vec::new();
+        times.for_each(|time| match onsets.last_mut() {
+            Some((last, strength)) if time - *last < ONSET_RESOLUTION => *strength += 1,
+            _ => onsets.push((time, 1)),
+        });
+
+        onsets
+    }
+
+    /// Probe sounds from their timings, getting their lengths and play times.
+    /// Also returns the sample rate of the first sound if none is passed, and the length of the song.
+    fn get_sounds(
+        timings: HashMap<(PathBuf, SoundModifier), Vec<f64>>,
+        mut sample_rate: Option<u32>,
+    ) -> (Vec<Sound>, Option<u32>, f64) {
         let mut song_length: f64 = 0.0;
 
-        // Convert the HashMap of paths and timings into a vector of probes and timings.
-        // Getting the probes before actually loading audio allows us to filter notes based on
+        // Convert the HashMap of paths and timings into a vector of sounds.
+        // Probing the sounds before actually loading audio allows us to filter notes based on
         // play time and sound length before putting effort into decoding.
-        let probes: Vec<(Probe, Vec<f64>)> = self
-            .get_wav_timings()
+        let sounds = timings
             .into_iter()
-            .filter_map(|(path, time_vec)| {
+            .filter_map(|((path, modifier), times)| {
                 let Ok(probe) = Probe::new(&path) else {
                     return None;
                 };
 
-                let Some(length) = probe.get_length() else {
-                    return None;
-                };
+                let length = probe.get_length(&modifier)?;
 
                 // If the sample rate is none, then we'll set it as the sample rate of the first
                 // sound that we come across here.
@@ -136,87 +278,717 @@ impl Renderer {
                 }
 
                 // The length of the song will be the maximum end time of any sound.
-                time_vec.iter().for_each(|time| {
+                times.iter().for_each(|time| {
                     song_length = song_length.max(*time + length);
                 });
 
-                Some((probe, time_vec.clone()))
+                Some(Sound {
+                    path,
+                    modifier,
+                    length,
+                    times,
+                })
             })
             .collect();
 
-        // Get the desired start and end of the preview.
-        // If start / end percentages are passed, then we'll set the start and end according to song length.
-        let mut start = args.start;
-        let mut end = args.end;
-        if let (Some(start_p), Some(end_p)) = (args.start_p, args.end_p) {
-            start = (start_p / 100.0) * song_length;
-            end = (end_p / 100.0) * song_length;
+        (sounds, sample_rate, song_length)
+    }
+
+    /// Mix the sounds played between the start and end of the song into a new buffer.
+    fn mix(sounds: &[Sound], start: f64, end: f64, sample_rate: u32) -> StereoAudio {
+        let mut render = StereoAudio {
+            buffer: Vec::new(),
+            sample_rate,
+        };
+        Mixer::new(sounds, start, end, sample_rate).for_each(|block| {
+            render.buffer.extend_from_slice(&block.buffer);
+        });
+
+        render
+    }
+
+    /// Get the start and end of the preview in seconds, as set by the settings.
+    fn get_window(
+        &self,
+        settings: &PreviewSettings,
+        spec: &WindowSpec,
+        sounds: &[Sound],
+        song_length: f64,
+    ) -> Result<(f64, f64), WindowError> {
+        // Get the desired start and end of the preview, filling in whatever wasn't given.
+        let (mut start, mut end) = spec.resolve(song_length, &self.timeline)?;
+
+        // If a highlight mode is set, we'll keep the length of the window and move it to the highlight.
+        if let Some(mode) = settings.auto {
+            let length = end - start;
+            let highlight = match mode {
+                HighlightMode::Density => {
+                    let events = self.get_density_events(settings.auto_bgm_weight);
+                    densest_window(&events, length, song_length)
+                }
+                HighlightMode::Energy | HighlightMode::Flux => {
+                    // Analyse a low rate mixdown of the whole song, mixed the same way as the preview.
+                    let mixdown = Renderer::mix(sounds, 0.0, song_length, ANALYSIS_SAMPLE_RATE);
+                    loudest_window(&mixdown, mode, length)
+                }
+            };
+            // Keep the window where it was if there's nothing to move it to.
+            start = highlight.unwrap_or(start);
+            end = start + length;
         }
 
-        // If the start and end aren't ordered, we'll swap them around.
-        if start > end {
-            let tmp = start;
-            start = end;
-            end = tmp;
+        // Make sure the window is within the song, otherwise we'd render silence.
+        (start, end) = fit_window(start, end, song_length);
+
+        // Nudge the window so that it starts on the strongest nearby note, rather than in the middle of a sound.
+        if settings.align_onset
+            && let Some(onset) =
+                strongest_onset(&self.get_onsets(), start, settings.onset_tolerance)
+        {
+            end += onset - start;
+            start = onset;
         }
 
-        // Create a new stereo buffer for our preview.
-        let mut render = StereoAudio::new(end - start, sample_rate.unwrap_or(48000));
-        // Iterate over all of the probes and play their timings.
-        probes.into_iter().for_each(|probe_time| {
-            let (probe, timings) = probe_time;
-            let Some(length) = probe.get_length() else {
-                return;
-            };
+        // Snap the window to the chart's grid so that the preview starts and ends on the beat.
+        if let Some(snap) = settings.snap {
+            (start, end) =
+                self.timeline
+                    .snap_window(start, end, snap, settings.phrase_length, song_length);
+        }
 
-            // Filter out times that don't fit within the preview.
-            let mut filtered_times = timings
+        // Aligning and snapping can move the window past the ends of the song again.
+        Ok(fit_window(start, end, song_length))
+    }
+
+    /// Plan the windows of the preview, as set by the settings.
+    /// Medley previews are made of several windows, which are joined together with crossfades.
+    fn get_plan(
+        &self,
+        settings: &PreviewSettings,
+        sounds: &[Sound],
+        song_length: f64,
+    ) -> Result<PreviewPlan, AudioError> {
+        if !settings.segments.is_empty() {
+            let windows = settings
+                .segments
                 .iter()
-                .filter(|time| **time < end && (**time + length) > start)
-                .peekable();
+                .map(|segment| {
+                    let (start, end) = segment.resolve(song_length, &self.timeline)?;
+                    let (start, end) = fit_window(start, end, song_length);
+                    let (start, end) = match settings.snap {
+                        Some(snap) => self.timeline.snap_window(
+                            start,
+                            end,
+                            snap,
+                            settings.phrase_length,
+                            song_length,
+                        ),
+                        None => (start, end),
+                    };
+                    Ok(fit_window(start, end, song_length))
+                })
+                .collect::<Result<_, WindowError>>()?;
 
-            // If no filtered times exist, then this sound isn't played during the preview,
-            // so we'll just return.
-            if filtered_times.peek().is_none() {
-                return;
+            return Ok(PreviewPlan {
+                windows,
+                crossfade: settings.segment_crossfade,
+                loop_crossfade: None,
+            });
+        }
+
+        let spec = WindowSpec::from_settings(settings)?;
+        let (mut start, mut end) = self.get_window(settings, &spec, sounds, song_length)?;
+
+        // Looping previews need to loop on a measure line.
+        if settings.loop_preview && settings.snap.is_none() {
+            (start, end) = self.timeline.snap_window(
+                start,
+                end,
+                SnapMode::Measure,
+                settings.phrase_length,
+                song_length,
+            );
+            (start, end) = fit_window(start, end, song_length);
+        }
+
+        Ok(PreviewPlan {
+            windows: vec![(start, end)],
+            crossfade: 0.0,
+            loop_crossfade: settings.loop_preview.then_some(settings.loop_crossfade),
+        })
+    }
+
+    /// Mix a preview, fading it and setting its volume, and pass each block to a closure once it's finished.
+    /// Returns whether any of the preview was audible.
+    fn render_preview(
+        settings: &PreviewSettings,
+        plan: &PreviewPlan,
+        sounds: &[Sound],
+        sample_rate: u32,
+        mut emit: impl FnMut(&StereoAudio) -> Result<(), AudioError>,
+    ) -> Result<bool, AudioError> {
+        let length = plan.length(sample_rate);
+        let mut position = 0;
+        let mut audible = false;
+
+        plan.mix(sounds, sample_rate, |mut block| {
+            audible |= !block.is_silent();
+
+            // Looping previews are crossfaded instead, as fades would cause a dip on every loop.
+            if plan.loop_crossfade.is_none() {
+                block.fade_at(position, length, settings.fade_in, settings.fade_out);
             }
+            block.attenuate(settings.volume / 100.0);
+            position += block.buffer.len();
 
-            let Ok(mut audio) = StereoAudio::load(probe) else {
-                return;
-            };
+            emit(&block)
+        })?;
 
-            if let Err(_) = audio.match_sample_rate(&render) {
-                return;
+        Ok(audible)
+    }
+
+    /// Re-encode a preview provided by the chart's author through our fades and volume.
+    /// The original is kept as a backup, which is used as the source if the preview is re-encoded again.
+    fn reencode_author_preview(
+        &self,
+        args: &Args,
+        author_preview: &str,
+    ) -> Result<Outcome, AudioError> {
+        let author_path = self.base_path.join(author_preview);
+
+        // Find a backup of the original, which may have any of the audio extensions.
+        let backup = [author_path.clone()]
+            .into_iter()
+            .chain(
+                VALID_AUDIO
+                    .iter()
+                    .map(|ext| author_path.with_extension(ext)),
+            )
+            .map(|path| backup_path(&path))
+            .find(|path| path.exists());
+
+        // If there's a backup, then the preview has already been re-encoded.
+        if backup.is_some() && !args.overwrite {
+            return Ok(Outcome::Skipped(
+                "author preview was already re-encoded".to_string(),
+            ));
+        }
+
+        let source = match &backup {
+            Some(backup) => backup.clone(),
+            None => get_audio_fuzzy(&author_path).ok_or(AudioError::FileNotFound())?,
+        };
+
+        // Keep the format of the #PREVIEW file if we can encode it, so the chart still points at it.
+        let format = args
+            .settings
+            .format
+            .or_else(|| {
+                let extension = author_path.extension()?.to_string_lossy();
+                OutputFormat::from_extension(&extension)
+            })
+            .unwrap_or(OutputFormat::Vorbis);
+        let mut audio = StereoAudio::load(Probe::new(&source)?)?;
+        let sample_rate =
+            format.sample_rate(args.settings.sample_rate.unwrap_or(audio.sample_rate));
+        audio.resample(sample_rate as usize)?;
+
+        // Keep a copy of the original before anything is written over it.
+        let new_backup = backup.is_none().then(|| backup_path(&source));
+        if let Some(new_backup) = &new_backup {
+            fs::copy(&source, new_backup)?;
+        }
+
+        audio.fade(args.settings.fade_in, args.settings.fade_out);
+        audio.attenuate(args.settings.volume / 100.0);
+        let settings = EncoderSettings {
+            tags: [
+                self.get_tags(args.songs_folder.as_deref(), &[]),
+                Renderer::get_replaygain_tags(&args.settings, format, &audio),
+            ]
+            .concat(),
+            ..EncoderSettings::from_preview_settings(&args.settings)
+        };
+        let output_path = author_path.with_extension(format.extension());
+        if let Err(e) = audio.encode(&output_path, format, &settings) {
+            // A backup marks the preview as re-encoded, so it can't be left behind by a failure.
+            if let Some(new_backup) = &new_backup {
+                let _ = fs::remove_file(new_backup);
             }
+            return Err(e);
+        }
 
-            filtered_times.for_each(|time| {
-                let _ = render.add(&audio, *time - start);
-            });
-        });
+        if output_path == author_path {
+            // The original may have only been found by another extension, so move it out of the way
+            // now that the chart's file exists.
+            if new_backup.is_some() && source != output_path {
+                fs::remove_file(&source)?;
+            }
+        } else {
+            // The format changed, so point the chart at the new file. The original is left in place
+            // for other charts in the folder that share it.
+            let preview_file = Path::new(author_preview).with_extension(format.extension());
+            write_preview_header(
+                &self.chart_path,
+                self.encoding,
+                &preview_file.to_string_lossy(),
+            )?;
+        }
+
+        Ok(Outcome::Rendered)
+    }
+
+    /// Find preview files in the song folder by the same rules that beatoraja uses, which picks up
+    /// any audio file whose name starts with "preview" when a chart doesn't have a #PREVIEW header.
+    fn find_existing_previews(&self) -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(&self.base_path) else {
+            return Vec::new();
+        };
+
+        entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let Some(name) = path.file_name() else {
+                    return false;
+                };
+                let name = name.to_string_lossy().to_lowercase();
+                let is_audio = path.extension().is_some_and(|ext| {
+                    PREVIEW_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+                });
+
+                name.starts_with("preview") && is_audio && path.is_file()
+            })
+            .collect()
+    }
+
+    /// Get tags describing the chart and how a file was made from it.
+    fn get_tags(&self, songs_folder: Option<&str>, windows: &[(f64, f64)]) -> Tags {
+        let info = &self.bms.music_info;
+        let mut tags = Tags::new();
+        let mut tag = |name: &str, value: Option<String>| {
+            if let Some(value) = value {
+                tags.push((name.to_string(), value));
+            }
+        };
+
+        // Paths are relative to the songs folder, so they don't depend on where the library is.
+        let chart_path = songs_folder
+            .and_then(|folder| self.chart_path.strip_prefix(folder).ok())
+            .unwrap_or(&self.chart_path);
+
+        tag("TITLE", info.title.clone());
+        tag("SUBTITLE", info.subtitle.clone());
+        tag("ARTIST", info.artist.clone());
+        tag("GENRE", info.genre.clone());
+        tag("BPM", self.bms.bpm.bpm.as_ref().map(|bpm| bpm.to_string()));
+        tag("ENCODER", Some(VENDOR.to_string()));
+        tag(
+            "SOURCE_CHART",
+            Some(chart_path.to_string_lossy().into_owned()),
+        );
+        tag("SOURCE_CHART_SHA256", Some(self.sha256.clone()));
+        tag(
+            "PREVIEW_WINDOW",
+            (!windows.is_empty()).then(|| describe_windows(windows)),
+        );
+
+        tags
+    }
+
+    /// Get ReplayGain tags for audio as it will be encoded, if they're enabled.
+    fn get_replaygain_tags(
+        settings: &PreviewSettings,
+        format: OutputFormat,
+        audio: &StereoAudio,
+    ) -> Tags {
+        if !settings.replaygain {
+            return Tags::new();
+        }
+
+        let mut meter = LoudnessMeter::new(audio.sample_rate, settings.mono_audio);
+        meter.add(&audio.buffer);
+        meter.replaygain_tags(format)
+    }
+
+    /// Get the time of the first sound played.
+    fn first_sound(sounds: &[Sound]) -> Option<f64> {
+        sounds
+            .iter()
+            .flat_map(|sound| sound.times.iter().copied())
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    /// Mix the sounds played between the start and end of the song straight into a file.
+    /// The mix is encoded block by block as it's rendered, so long mixes don't need to fit in memory.
+    fn stream_mix(
+        settings: &PreviewSettings,
+        sounds: &[Sound],
+        (start, end): (f64, f64),
+        sample_rate: u32,
+        path: &Path,
+        format: OutputFormat,
+        mut tags: Tags,
+    ) -> Result<(), AudioError> {
+        // Tags are written before the audio, so the mix is measured in a pass of its own.
+        if settings.replaygain {
+            let mut meter = LoudnessMeter::new(sample_rate, settings.mono_audio);
+            for mut block in Mixer::new(sounds, start, end, sample_rate) {
+                block.attenuate(settings.volume / 100.0);
+                meter.add(&block.buffer);
+            }
+            tags.extend(meter.replaygain_tags(format));
+        }
+
+        let encoder_settings = EncoderSettings {
+            tags,
+            ..EncoderSettings::from_preview_settings(settings)
+        };
+        let mut encoder = create_encoder(path, format, sample_rate, &encoder_settings)?;
+        for mut block in Mixer::new(sounds, start, end, sample_rate) {
+            block.attenuate(settings.volume / 100.0);
+            encoder.write(&block.buffer)?;
+        }
+
+        encoder.finish()
+    }
+
+    /// Render the whole chart from its first sound to its last into a bounce file.
+    fn bounce(&self, args: &Args) -> Result<Outcome, AudioError> {
+        let (format, bounce_file) =
+            OutputFormat::choose(args.settings.format, &self.file_name(&args.bounce_file));
+        let bounce_path = self.output_folder(args).join(bounce_file);
+        if !args.overwrite && bounce_path.exists() {
+            return Ok(Outcome::Skipped("bounce already exists".to_string()));
+        }
+
+        // Keysounds are included, so the bounce sounds like the stems played together.
+        let notes = &self.bms.wav.notes;
+        let timings = self.get_wav_timings(
+            notes
+                .playables::<KeyLayoutBeat>()
+                .chain(notes.bgms::<KeyLayoutBeat>()),
+        );
+        let (sounds, sample_rate, song_length) =
+            Renderer::get_sounds(timings, args.settings.sample_rate);
+        let sample_rate = format.sample_rate(sample_rate.unwrap_or(48000));
+
+        // Leading silence before the first sound isn't part of the song.
+        let start = Renderer::first_sound(&sounds).ok_or(AudioError::SilentRender())?;
+        let window = (start.max(0.0), song_length);
+        if let Some(folder) = bounce_path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        Renderer::stream_mix(
+            &args.settings,
+            &sounds,
+            window,
+            sample_rate,
+            &bounce_path,
+            format,
+            self.get_tags(args.songs_folder.as_deref(), &[window]),
+        )?;
+
+        Ok(Outcome::Rendered)
+    }
+
+    /// Render the BGM and each lane of the chart into separate stem files.
+    /// Every stem covers the same span of the song, so they line up when played together.
+    fn render_stems(&self, args: &Args) -> Result<Outcome, AudioError> {
+        let stems_path = self.output_folder(args).join(&args.stems_folder);
+        if !args.overwrite && stems_path.exists() {
+            return Ok(Outcome::Skipped("stems already exist".to_string()));
+        }
+
+        let mut sample_rate = args.settings.sample_rate;
+        let mut song_length: f64 = 0.0;
+        let mut stems: Vec<(String, Vec<Sound>)> = Vec::new();
+        for (name, timings) in self.get_stem_timings() {
+            let (sounds, stem_rate, stem_length) = Renderer::get_sounds(timings, sample_rate);
+            sample_rate = stem_rate;
+            song_length = song_length.max(stem_length);
+            stems.push((name, sounds));
+        }
+        let format = args.settings.format.unwrap_or(OutputFormat::Vorbis);
+        let sample_rate = format.sample_rate(sample_rate.unwrap_or(48000));
+
+        let start = stems
+            .iter()
+            .filter_map(|(_, sounds)| Renderer::first_sound(sounds))
+            .min_by(|a, b| a.total_cmp(b))
+            .ok_or(AudioError::SilentRender())?;
+        let window = (start.max(0.0), song_length);
+
+        fs::create_dir_all(&stems_path)?;
+        for (name, sounds) in &stems {
+            let path = stems_path.join(format!("{}.{}", name, format.extension()));
+            let mut tags = self.get_tags(args.songs_folder.as_deref(), &[window]);
+            tags.push(("STEM".to_string(), name.clone()));
+
+            Renderer::stream_mix(
+                &args.settings,
+                sounds,
+                window,
+                sample_rate,
+                &path,
+                format,
+                tags,
+            )?;
+        }
+
+        Ok(Outcome::Rendered)
+    }
 
-        // Fade the start and end, set the volume, and output the final preview audio.
-        render.fade(args.fade_in, args.fade_out);
-        render.attenuate(args.volume / 100.0);
-        render.encode(preview_path, args.mono_audio)?;
+    /// Get the format and file name of the preview, as set by the arguments.
+    fn preview_file(&self, args: &Args) -> (OutputFormat, String) {
+        OutputFormat::choose(args.settings.format, &self.file_name(&args.preview_file))
+    }
+
+    /// Get the sounds of the chart, the sample rate to render at in a format, and the windows of the preview.
+    fn prepare_preview(
+        &self,
+        settings: &PreviewSettings,
+        format: OutputFormat,
+    ) -> Result<(Vec<Sound>, u32, PreviewPlan), AudioError> {
+        let (sounds, sample_rate, song_length) = Renderer::get_sounds(
+            self.get_wav_timings(self.bms.wav.notes.bgms::<KeyLayoutBeat>()),
+            settings.sample_rate,
+        );
+        let sample_rate = format.sample_rate(sample_rate.unwrap_or(48000));
+        let plan = self.get_plan(settings, &sounds, song_length)?;
+
+        Ok((sounds, sample_rate, plan))
+    }
+
+    /// Render the preview and encode it in a format, with an encoder created by a closure from the
+    /// sample rate and settings to encode with. Sidecars are created by another closure from the sample
+    /// rate and length of the preview, and are analysed along the way.
+    /// Tagged chart paths are relative to the songs folder, if there is one.
+    fn encode_preview(
+        &self,
+        settings: &PreviewSettings,
+        songs_folder: Option<&str>,
+        format: OutputFormat,
+        new_sidecars: impl FnOnce(u32, usize) -> Sidecars,
+        new_encoder: impl FnOnce(u32, &EncoderSettings) -> Result<Box<dyn Encoder>, AudioError>,
+    ) -> Result<Sidecars, AudioError> {
+        let (sounds, sample_rate, plan) = self.prepare_preview(settings, format)?;
+
+        // Tags are written before the audio, so the preview is measured in a pass of its own.
+        let mut tags = self.get_tags(songs_folder, &plan.windows);
+        if settings.replaygain {
+            let mut meter = LoudnessMeter::new(sample_rate, settings.mono_audio);
+            Renderer::render_preview(settings, &plan, &sounds, sample_rate, |block| {
+                meter.add(&block.buffer);
+                Ok(())
+            })?;
+            tags.extend(meter.replaygain_tags(format));
+        }
+
+        // The preview is encoded block by block as it's mixed, so it never has to be held in memory.
+        let encoder_settings = EncoderSettings {
+            tags,
+            ..EncoderSettings::from_preview_settings(settings)
+        };
+        let mut encoder = new_encoder(sample_rate, &encoder_settings)?;
+        let mut sidecars = new_sidecars(sample_rate, plan.length(sample_rate));
+        let audible = Renderer::render_preview(settings, &plan, &sounds, sample_rate, |block| {
+            sidecars.add(&block.buffer);
+            encoder.write(&block.buffer)
+        })?;
+
+        // A silent preview is worse than none, since it stops players from falling back on anything else.
+        // Dropping the encoder without finishing it discards the output.
+        if !audible {
+            return Err(AudioError::SilentRender());
+        }
+        encoder.finish()?;
+
+        Ok(sidecars)
+    }
+
+    /// Render the preview as set by the settings into memory, without writing any files.
+    /// The audio is faded and attenuated, at the sample rate it would be encoded at in the settings' format.
+    pub fn render_audio(&self, settings: &PreviewSettings) -> Result<StereoAudio, AudioError> {
+        let format = settings.format.unwrap_or(OutputFormat::Vorbis);
+        let (sounds, sample_rate, plan) = self.prepare_preview(settings, format)?;
+
+        let mut audio = StereoAudio {
+            buffer: Vec::with_capacity(plan.length(sample_rate)),
+            sample_rate,
+        };
+        let audible = Renderer::render_preview(settings, &plan, &sounds, sample_rate, |block| {
+            audio.buffer.extend_from_slice(&block.buffer);
+            Ok(())
+        })?;
+        if !audible {
+            return Err(AudioError::SilentRender());
+        }
+
+        Ok(audio)
+    }
+
+    /// Render and encode the preview as set by the settings into memory, without writing any files.
+    /// The format is the settings' format, or Vorbis if it isn't set, and is returned along with the encoded file.
+    pub fn render_bytes(
+        &self,
+        settings: &PreviewSettings,
+    ) -> Result<(Vec<u8>, OutputFormat), AudioError> {
+        let format = settings.format.unwrap_or(OutputFormat::Vorbis);
+        let output = SharedWriter::new(Cursor::new(Vec::new()));
+        self.encode_preview(
+            settings,
+            None,
+            format,
+            |_, _| Sidecars::default(),
+            |sample_rate, encoder_settings| {
+                create_output_encoder(output.clone(), format, sample_rate, encoder_settings)
+            },
+        )?;
+
+        // The encoder has finished and been dropped, so its copy of the output should be gone.
+        let bytes = output
+            .into_inner()
+            .ok_or(AudioError::OutputInUse())?
+            .into_inner();
+        Ok((bytes, format))
+    }
+
+    /// Render and encode the preview as set by the settings into a writer, such as a network stream.
+    /// Some formats go back to update their headers, so the file is encoded in memory before it's written.
+    pub fn render_to_writer(
+        &self,
+        settings: &PreviewSettings,
+        writer: &mut impl Write,
+    ) -> Result<OutputFormat, AudioError> {
+        let (bytes, format) = self.render_bytes(settings)?;
+        writer.write_all(&bytes)?;
+
+        Ok(format)
+    }
+
+    /// Process a BMS file, outputting an audio preview file.
+    pub fn process_bms_file(&self, args: &Args) -> Result<Outcome, AudioError> {
+        // Bounces and stems are rendered instead of the preview, so none of the preview checks apply.
+        if args.bounce {
+            return self.bounce(args);
+        }
+        if args.stems {
+            return self.render_stems(args);
+        }
+
+        let (format, preview_file) = self.preview_file(args);
+        let preview_path = self.output_folder(args).join(&preview_file);
+        // Where players look for the preview, which is only written to if it's the output path or linked to it.
+        let song_preview_path = self.base_path.join(&preview_file);
+        let writes_song_folder = args.output_dir.is_none() || args.link.is_some();
+        // Files named by our template are ours, such as the previews of other charts in the folder.
+        let template = OutputFormat::choose(args.settings.format, &args.preview_file).1;
+        // If the BMS file has a preview set, then that'll be played by default, regardless of if we generate a preview.
+        // We may still re-encode it, or generate our own if the file it points to is missing.
+        // A #PREVIEW written by --write-preview-header points at our own preview, which is regenerated as usual.
+        if let Some(author_preview) = &self.bms.music_info.preview_music
+            && !Path::new(author_preview)
+                .file_name()
+                .is_some_and(|name| matches_file_template(&template, &name.to_string_lossy()))
+        {
+            let author_path = self.base_path.join(author_preview);
+            let exists = get_audio_fuzzy(&author_path).is_some();
+
+            if exists && args.reencode_author_preview {
+                return self.reencode_author_preview(args, author_preview);
+            }
+            if exists || !args.generate_missing_preview {
+                return Ok(Outcome::Skipped("chart has its own #PREVIEW".to_string()));
+            }
+        }
+        // If we don't allow overwrites, and a preview already exists by the same name, we'll skip.
+        if !args.overwrite
+            && (preview_path.exists() || (writes_song_folder && song_preview_path.exists()))
+        {
+            return Ok(Outcome::Skipped("preview already exists".to_string()));
+        }
+
+        // Other preview files would be picked up by players instead of, or alongside, ours.
+        // Previews that are only written to the output folder aren't seen by players, so they don't clash.
+        let existing_previews: Vec<PathBuf> = if writes_song_folder {
+            self.find_existing_previews()
+                .into_iter()
+                .filter(|path| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    *path != song_preview_path && !matches_file_template(&template, &name)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if args.existing == ExistingPreviews::Skip
+            && let Some(existing) = existing_previews.first()
+        {
+            return Ok(Outcome::Skipped(format!(
+                "found existing preview {}",
+                existing.file_name().unwrap_or_default().to_string_lossy()
+            )));
+        }
 
-        Ok(())
+        if let Some(folder) = preview_path.parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let sidecars = self.encode_preview(
+            &args.settings,
+            args.songs_folder.as_deref(),
+            format,
+            |sample_rate, length| Sidecars::new(args, sample_rate, length),
+            |sample_rate, encoder_settings| {
+                create_encoder(&preview_path, format, sample_rate, encoder_settings)
+            },
+        )?;
+        let sidecar_paths = sidecars.write(&preview_path)?;
+
+        // The sidecars are linked along with the preview, so they stay next to it in the song folder.
+        if args.output_dir.is_some()
+            && let Some(mode) = args.link
+        {
+            link_file(&preview_path, &song_preview_path, mode)?;
+            for sidecar_path in sidecar_paths {
+                let extension = sidecar_path.extension().unwrap_or_default();
+                link_file(
+                    &sidecar_path,
+                    &song_preview_path.with_extension(extension),
+                    mode,
+                )?;
+            }
+        }
+
+        // Move other previews out of the way, so that players only find ours.
+        if args.existing == ExistingPreviews::Replace {
+            for existing in existing_previews {
+                fs::rename(&existing, backup_path(&existing))?;
+            }
+        }
+
+        // Point the chart at the new preview, so players don't have to rely on file names.
+        if args.write_preview_header {
+            write_preview_header(&self.chart_path, self.encoding, &preview_file)?;
+        }
+
+        Ok(Outcome::Rendered)
     }
 
-    /// Decode a string.
-    fn decode(bytes: &Vec<u8>) -> Result<String, RendererError>{
+    /// Decode a string, returning the encoding it was decoded with.
+    fn decode(bytes: &[u8]) -> Result<(String, &'static Encoding), RendererError> {
         // Create a new detector and feed it the byte sequence
         let mut detector = EncodingDetector::new();
-        detector.feed(&bytes, true);
-        
+        detector.feed(bytes, true);
+
         // Guess the encoding and decode it
         let encoding = detector.guess(None, true);
-        let (source, _, failure) = encoding.decode(bytes);
+        let (source, encoding, failure) = encoding.decode(bytes);
 
         if !failure {
-            return Ok(source.to_string());
+            return Ok((source.to_string(), encoding));
         }
 
-        return Err(RendererError::BMSDecodingError());
+        Err(RendererError::BMSDecodingError())
     }
 
     /// Create a new renderer, parsing the BMS file.
@@ -230,11 +1002,14 @@ impl Renderer {
         let file_bytes = fs::read(path_ref)?;
 
         // Decode the file with the proper encoding.
-        let source = Renderer::decode(&file_bytes)?;
+        let (source, encoding) = Renderer::decode(&file_bytes)?;
+        let md5 = format!("{:x}", Md5::digest(&file_bytes));
+        let sha256 = format!("{:x}", Sha256::digest(&file_bytes));
 
         // Parse the BMS file.
         // We handle BMSON files separately, and then convert to BMS.
         let bms;
+        let mut extended_wav = ExtendedWavDefs::default();
         if extension == "bmson" {
             let bmson = parse_bmson(&source)
                 .bmson
@@ -242,11 +1017,20 @@ impl Renderer {
             bms = Bms::from_bmson(bmson).bms;
         } else {
             bms = parse_bms(&source, default_config()).bms?;
+            extended_wav = ExtendedWavDefs::parse(&source);
         }
 
+        let timeline = Timeline::new(&bms);
+
         Ok(Self {
             bms,
             base_path: path_ref.parent().unwrap().to_path_buf(),
+            chart_path: path_ref.to_path_buf(),
+            encoding,
+            extended_wav,
+            timeline,
+            md5,
+            sha256,
         })
     }
 }
@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use realfft::{RealFftPlanner, RealToComplex};
+use serde::Serialize;
+
+use crate::bms_preview::Args;
+use crate::bms_preview::atomic_file::write_atomic;
+use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::stereo_audio::StereoSample;
+
+/// The number of samples in each frame of a spectrogram.
+const SPECTROGRAM_FRAME_SIZE: usize = 2048;
+/// The lowest frequency shown in spectrograms, as frequencies are shown on a log scale.
+const SPECTROGRAM_MIN_FREQUENCY: f32 = 20.0;
+/// The quietest level shown in spectrograms (dBFS), which is drawn as black.
+const SPECTROGRAM_FLOOR: f32 = -96.0;
+/// The colours that spectrogram levels fade between, from quietest to loudest.
+const SPECTROGRAM_COLOURS: [[f32; 3]; 5] = [
+    [0.0, 0.0, 0.0],
+    [40.0, 0.0, 90.0],
+    [190.0, 30.0, 60.0],
+    [250.0, 150.0, 0.0],
+    [255.0, 255.0, 200.0],
+];
+const WAVEFORM_BACKGROUND: [u8; 3] = [20, 20, 28];
+const WAVEFORM_COLOUR: [u8; 3] = [90, 200, 255];
+
+/// The kinds of image that can be drawn of a preview.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    /// The waveform of the preview, drawn from its peaks.
+    Waveform,
+    /// A spectrogram of the preview, with frequencies on a log scale.
+    Spectrogram,
+}
+
+/// Waveform peaks in the JSON format of audiowaveform, which waveform viewers such as peaks.js can read.
+#[derive(Serialize)]
+struct PeaksFile<'a> {
+    version: u32,
+    channels: usize,
+    sample_rate: u32,
+    samples_per_pixel: usize,
+    bits: u32,
+    length: usize,
+    data: &'a [i16],
+}
+
+/// Collects the lowest and highest sample of each channel over every span of samples.
+struct Peaks {
+    samples_per_peak: usize,
+    mono: bool,
+    /// The (min, max) of each channel over the current span.
+    current: Vec<(f32, f32)>,
+    count: usize,
+    /// The min and max of each channel, for each span in turn.
+    data: Vec<i16>,
+}
+
+impl Peaks {
+    fn new(samples_per_peak: usize, mono: bool) -> Self {
+        let channels = if mono { 1 } else { 2 };
+
+        Self {
+            samples_per_peak: samples_per_peak.max(1),
+            mono,
+            current: vec![(f32::INFINITY, f32::NEG_INFINITY); channels],
+            count: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, sample: &StereoSample) {
+        let values = if self.mono {
+            [(sample.left + sample.right) / 2.0; 2]
+        } else {
+            [sample.left, sample.right]
+        };
+        self.current
+            .iter_mut()
+            .zip(values)
+            .for_each(|((min, max), value)| {
+                *min = min.min(value);
+                *max = max.max(value);
+            });
+
+        self.count += 1;
+        if self.count == self.samples_per_peak {
+            self.flush();
+        }
+    }
+
+    /// Finish the current span, even if it isn't full.
+    fn flush(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+
+        let to_int = |value: f32| (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16;
+        self.current.iter_mut().for_each(|(min, max)| {
+            self.data.push(to_int(*min));
+            self.data.push(to_int(*max));
+            (*min, *max) = (f32::INFINITY, f32::NEG_INFINITY);
+        });
+        self.count = 0;
+    }
+
+    fn into_json(mut self, sample_rate: u32) -> Result<String, AudioError> {
+        self.flush();
+        let channels = self.current.len();
+
+        Ok(serde_json::to_string(&PeaksFile {
+            version: 2,
+            channels,
+            sample_rate,
+            samples_per_pixel: self.samples_per_peak,
+            bits: 16,
+            length: self.data.len() / (channels * 2),
+            data: &self.data,
+        })?)
+    }
+}
+
+/// Computes the spectrum of audio at evenly spaced columns, as it's added.
+struct Spectrogram {
+    fft: Arc<dyn RealToComplex<f32>>,
+    /// The most recent samples, in mono.
+    recent: VecDeque<f32>,
+    columns: usize,
+    length: usize,
+    position: usize,
+    /// The level of each frequency bin (dBFS) of each finished column.
+    spectra: Vec<Vec<f32>>,
+}
+
+impl Spectrogram {
+    fn new(columns: usize, length: usize) -> Self {
+        Self {
+            fft: RealFftPlanner::<f32>::new().plan_fft_forward(SPECTROGRAM_FRAME_SIZE),
+            recent: VecDeque::with_capacity(SPECTROGRAM_FRAME_SIZE),
+            columns,
+            length,
+            position: 0,
+            spectra: Vec::with_capacity(columns),
+        }
+    }
+
+    /// Get the position that a column's frame ends at, with the frame centred on the column.
+    fn column_end(&self, column: usize) -> usize {
+        let centre = (column * 2 + 1) * self.length / (self.columns * 2);
+        (centre + SPECTROGRAM_FRAME_SIZE / 2).min(self.length)
+    }
+
+    fn add(&mut self, sample: &StereoSample) {
+        if self.recent.len() == SPECTROGRAM_FRAME_SIZE {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((sample.left + sample.right) / 2.0);
+        self.position += 1;
+
+        while self.spectra.len() < self.columns
+            && self.position >= self.column_end(self.spectra.len())
+        {
+            self.analyse();
+        }
+    }
+
+    /// Compute the spectrum of the most recent frame as the next column.
+    fn analyse(&mut self) {
+        let mut input = self.fft.make_input_vec();
+        let mut spectrum = self.fft.make_output_vec();
+
+        // Apply a Hann window, padding the start with silence if there isn't a whole frame yet.
+        let padding = SPECTROGRAM_FRAME_SIZE - self.recent.len();
+        input.iter_mut().enumerate().for_each(|(i, value)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f32 / SPECTROGRAM_FRAME_SIZE as f32).cos();
+            let sample = i
+                .checked_sub(padding)
+                .and_then(|i| self.recent.get(i))
+                .copied()
+                .unwrap_or_default();
+            *value = sample * window;
+        });
+
+        // A full scale sine has a magnitude of a quarter of the frame size with a Hann window.
+        let full_scale = SPECTROGRAM_FRAME_SIZE as f32 / 4.0;
+        let levels = match self.fft.process(&mut input, &mut spectrum) {
+            Ok(_) => spectrum
+                .iter()
+                .map(|bin| 20.0 * (bin.norm() / full_scale).max(1e-10).log10())
+                .collect(),
+            Err(_) => vec![SPECTROGRAM_FLOOR; spectrum.len()],
+        };
+        self.spectra.push(levels);
+    }
+
+    /// Draw the spectrogram as RGB pixels, with low frequencies at the bottom.
+    fn draw(mut self, height: usize, sample_rate: u32) -> Vec<u8> {
+        while self.spectra.len() < self.columns {
+            self.analyse();
+        }
+
+        let nyquist = sample_rate as f32 / 2.0;
+        let bins = SPECTROGRAM_FRAME_SIZE / 2;
+        let row_bins: Vec<usize> = (0..height)
+            .map(|row| {
+                let height_ratio = 1.0 - (row as f32 + 0.5) / height as f32;
+                let frequency = SPECTROGRAM_MIN_FREQUENCY
+                    * (nyquist / SPECTROGRAM_MIN_FREQUENCY).powf(height_ratio);
+                ((frequency / nyquist * bins as f32).round() as usize).min(bins)
+            })
+            .collect();
+
+        let mut pixels = Vec::with_capacity(self.columns * height * 3);
+        row_bins.iter().for_each(|bin| {
+            self.spectra.iter().for_each(|levels| {
+                let level = (levels[*bin] - SPECTROGRAM_FLOOR) / -SPECTROGRAM_FLOOR;
+                pixels.extend(spectrogram_colour(level));
+            });
+        });
+
+        pixels
+    }
+}
+
+/// Get the colour of a spectrogram level, from 0 (quietest) to 1 (loudest).
+fn spectrogram_colour(level: f32) -> [u8; 3] {
+    let position = level.clamp(0.0, 1.0) * (SPECTROGRAM_COLOURS.len() - 1) as f32;
+    let index = (position as usize).min(SPECTROGRAM_COLOURS.len() - 2);
+    let ratio = position - index as f32;
+    let (from, to) = (SPECTROGRAM_COLOURS[index], SPECTROGRAM_COLOURS[index + 1]);
+
+    [0, 1, 2].map(|i| (from[i] + (to[i] - from[i]) * ratio).round() as u8)
+}
+
+/// Draw a waveform from the peaks of each column, as RGB pixels.
+fn draw_waveform(peaks: &mut Peaks, width: usize, height: usize) -> Vec<u8> {
+    peaks.flush();
+
+    let mut pixels: Vec<u8> = WAVEFORM_BACKGROUND.repeat(width * height);
+    let to_row = |value: i16| {
+        let ratio = 0.5 - value as f32 / i16::MAX as f32 / 2.0;
+        ((ratio * height as f32) as usize).min(height - 1)
+    };
+    peaks
+        .data
+        .chunks_exact(2)
+        .take(width)
+        .enumerate()
+        .for_each(|(column, peak)| {
+            (to_row(peak[1])..=to_row(peak[0])).for_each(|row| {
+                let pixel = (row * width + column) * 3;
+                pixels[pixel..pixel + 3].copy_from_slice(&WAVEFORM_COLOUR);
+            });
+        });
+
+    pixels
+}
+
+/// Encode RGB pixels as a PNG.
+fn encode_png(pixels: &[u8], width: usize, height: usize) -> Result<Vec<u8>, AudioError> {
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+    writer.finish()?;
+
+    Ok(bytes)
+}
+
+/// Files that describe a preview, written alongside it. They're analysed from the same blocks that
+/// are encoded, so the preview only has to be rendered once. The default is to have none.
+#[derive(Default)]
+pub struct Sidecars {
+    sample_rate: u32,
+    peaks: Option<Peaks>,
+    waveform: Option<Peaks>,
+    spectrogram: Option<Spectrogram>,
+    width: usize,
+    height: usize,
+}
+
+impl Sidecars {
+    /// Set up the sidecars set by the arguments, for a preview at a sample rate with a length in samples.
+    pub fn new(args: &Args, sample_rate: u32, length: usize) -> Self {
+        let width = args.image_width as usize;
+
+        Self {
+            sample_rate,
+            peaks: args
+                .peaks
+                .then(|| Peaks::new(args.peaks_samples as usize, args.settings.mono_audio)),
+            waveform: (args.image == Some(ImageKind::Waveform))
+                .then(|| Peaks::new(length.div_ceil(width), true)),
+            spectrogram: (args.image == Some(ImageKind::Spectrogram))
+                .then(|| Spectrogram::new(width, length)),
+            width,
+            height: args.image_height as usize,
+        }
+    }
+
+    /// Analyse a block of samples.
+    pub fn add(&mut self, samples: &[StereoSample]) {
+        samples.iter().for_each(|sample| {
+            if let Some(peaks) = &mut self.peaks {
+                peaks.add(sample);
+            }
+            if let Some(waveform) = &mut self.waveform {
+                waveform.add(sample);
+            }
+            if let Some(spectrogram) = &mut self.spectrogram {
+                spectrogram.add(sample);
+            }
+        });
+    }
+
+    /// Write the sidecars next to a preview, named after it, returning the paths that were written.
+    pub fn write(self, preview_path: &Path) -> Result<Vec<PathBuf>, AudioError> {
+        let mut written = Vec::new();
+
+        if let Some(peaks) = self.peaks {
+            let path = preview_path.with_extension("json");
+            write_atomic(&path, peaks.into_json(self.sample_rate)?)?;
+            written.push(path);
+        }
+
+        let pixels = match (self.waveform, self.spectrogram) {
+            (Some(mut waveform), _) => draw_waveform(&mut waveform, self.width, self.height),
+            (_, Some(spectrogram)) => spectrogram.draw(self.height, self.sample_rate),
+            _ => return Ok(written),
+        };
+        let path = preview_path.with_extension("png");
+        write_atomic(&path, encode_png(&pixels, self.width, self.height)?)?;
+        written.push(path);
+
+        Ok(written)
+    }
+}
@@ -1,12 +1,10 @@
 use std::{
     fs::File,
-    num::{NonZeroU8, NonZeroU32},
     ops::{Add, AddAssign, Mul, MulAssign},
     path::{Path, PathBuf},
 };
 
 use audioadapter_buffers::direct::SequentialSliceOfVecs;
-use itertools::Itertools;
 use rubato::{Fft, FixedSync, Resampler};
 use symphonia::core::{
     audio::SampleBuffer,
@@ -16,25 +14,26 @@ use symphonia::core::{
     meta::MetadataOptions,
     probe::Hint,
 };
-use vorbis_rs::VorbisEncoderBuilder;
 
+use crate::bms_preview::encoder::{EncoderSettings, OutputFormat, create_encoder};
 use crate::bms_preview::errors::AudioError;
+use crate::bms_preview::extended_wav::SoundModifier;
 
 const STEREO_CHANNELS: usize = 2;
 const RESAMPLING_CHUNK_SIZE: usize = 1024;
 const RESAMPLING_SUB_CHUNKS: usize = 1;
-const ENCODING_CHUNK_SIZE: usize = 1024;
+
+/// Audio extensions that are used in place of each other when a file is missing.
+pub const VALID_AUDIO: [&str; 3] = ["wav", "ogg", "mp3"];
 
 /// Find an audio file by path, but allow other valid audio extensions to pass
-fn get_audio_fuzzy(path: impl AsRef<Path>) -> Option<PathBuf> {
+pub fn get_audio_fuzzy(path: impl AsRef<Path>) -> Option<PathBuf> {
     let path_ref = path.as_ref();
 
     if path_ref.exists() {
         return Some(path_ref.to_path_buf());
     }
 
-    const VALID_AUDIO: [&str; 3] = ["wav", "ogg", "mp3"];
-
     // Find the first path with an alternate extension that exists
     VALID_AUDIO.iter().find_map(|extension| {
         let alternate_path = path_ref.with_extension(extension);
@@ -83,7 +82,7 @@ impl Probe {
 
         // Probe audio information
         let probed =
-            symphonia::default::get_probe().format(&hint, mss, &format_opts, &metadata_opts)?;
+            symphonia::default::get_probe().format(hint, mss, &format_opts, &metadata_opts)?;
 
         let format = probed.format;
         let track = format.default_track().unwrap();
@@ -94,14 +93,18 @@ impl Probe {
         })
     }
 
-    /// Get the length of an audio file.
-    pub fn get_length(&self) -> Option<f64> {
+    /// Get the length of an audio file, once played with its modifiers.
+    pub fn get_length(&self, modifier: &SoundModifier) -> Option<f64> {
         let codec = &self.track.codec_params;
-        let Some(frames) = codec.n_frames else {
-            return None;
-        };
+        let frames = codec.n_frames?;
 
-        Some(codec.time_base?.calc_time(frames).frac)
+        let time = codec.time_base?.calc_time(frames);
+        let length = time.seconds as f64 + time.frac;
+
+        match codec.sample_rate {
+            Some(sample_rate) => Some(modifier.playback_length(length, sample_rate)),
+            None => Some(length),
+        }
     }
 }
 
@@ -140,16 +143,6 @@ impl MulAssign<f32> for StereoSample {
 }
 
 impl StereoAudio {
-    /// Create a blank stereo audio of a certain length.
-    pub fn new(length: f64, sample_rate: u32) -> Self {
-        let samples = length * STEREO_CHANNELS as f64 * sample_rate as f64;
-
-        Self {
-            buffer: vec![Default::default(); samples as usize + 1],
-            sample_rate: sample_rate,
-        }
-    }
-
     /// Load stereo audio from probed data.
     pub fn load(mut probe: Probe) -> Result<Self, AudioError> {
         let decoder_opts: DecoderOptions = Default::default();
@@ -176,13 +169,8 @@ impl StereoAudio {
         let mut output: Vec<StereoSample> = Vec::new();
         let mut buffer: Option<SampleBuffer<f32>> = None;
 
-        loop {
-            // Get the next packet
-            let packet = match probe.format.next_packet() {
-                Ok(p) => p,
-                Err(_) => break,
-            };
-
+        // Get the next packet
+        while let Ok(packet) = probe.format.next_packet() {
             // In theory, the default track should be all that matters.
             // I'm not sure if there's an edge case here. I'm inclined to assume
             // BMS also only cares about default tracks, though.
@@ -247,7 +235,7 @@ impl StereoAudio {
         // Setup resampler.
         let mut resampler = Fft::<f32>::new(
             self.sample_rate as usize,
-            desired_rate as usize,
+            desired_rate,
             RESAMPLING_CHUNK_SIZE,
             RESAMPLING_SUB_CHUNKS,
             STEREO_CHANNELS,
@@ -296,41 +284,45 @@ impl StereoAudio {
     }
 
     pub fn fade(&mut self, fade_in_time: f64, fade_out_time: f64) {
-        // Get the length in samples of fades.
-        let in_samples = self.time_to_samples(fade_in_time);
-        let out_samples = self.time_to_samples(fade_out_time);
+        self.fade_at(0, self.buffer.len(), fade_in_time, fade_out_time);
+    }
 
-        // Iterate over the first in_samples samples and attenuate them linearly.
-        self.buffer
-            .iter_mut()
-            .zip(0..in_samples)
-            .for_each(|(sample, i)| {
-                let ratio = i as f32 / in_samples as f32;
-                *sample *= ratio;
-            });
+    /// Fade a block of audio which starts at a position within a longer audio, given that audio's total length
+    /// in samples. This lets audio be faded block by block as it's streamed.
+    pub fn fade_at(
+        &mut self,
+        position: usize,
+        total_length: usize,
+        fade_in_time: f64,
+        fade_out_time: f64,
+    ) {
+        // Get the length in samples of fades.
+        let in_samples = self.time_to_samples(fade_in_time).max(0) as usize;
+        let out_samples = self.time_to_samples(fade_out_time).max(0) as usize;
+
+        // Attenuate the first in_samples and the last out_samples samples linearly.
+        self.buffer.iter_mut().enumerate().for_each(|(i, sample)| {
+            let index = position + i;
+            if index < in_samples {
+                *sample *= index as f32 / in_samples as f32;
+            }
 
-        // Iterate over the last out_samples samples in reverse (since the index is still ascending).
-        self.buffer
-            .iter_mut()
-            .rev()
-            .zip(0..out_samples)
-            .for_each(|(sample, i)| {
-                let ratio = i as f32 / in_samples as f32;
-                *sample *= ratio;
-            });
+            let from_end = total_length.saturating_sub(index + 1);
+            if from_end < out_samples {
+                *sample *= from_end as f32 / out_samples as f32;
+            }
+        });
     }
 
-    pub fn add(&mut self, rhs: &StereoAudio, offset: f64) -> Result<(), AudioError> {
+    /// Add audio to the buffer at an offset in samples.
+    /// Negative offset will cut off the start of the added audio.
+    pub fn add_samples(&mut self, rhs: &StereoAudio, raw_offset: isize) -> Result<(), AudioError> {
         // We can't add two audios with different sample rates without resampling.
         if self.sample_rate != rhs.sample_rate {
             return Err(AudioError::MismatchedSampleRate());
         }
 
-        // Get the offset in samples of the audio to add, with respect to Self.
-        // Negative offset will cut off the start of the added audio.
-        let raw_offset = self.time_to_samples(offset);
-
-        let mut dst_offset = raw_offset.abs() as usize;
+        let mut dst_offset = raw_offset.unsigned_abs();
         let mut src_offset = dst_offset;
 
         // If the raw offset is positive, then we want destination offset equal to raw offset
@@ -355,107 +347,77 @@ impl StereoAudio {
         Ok(())
     }
 
-    pub fn attenuate(&mut self, volume: f32) {
-        // No need to do work if volume is 1.
-        if volume == 1.0 {
+    /// Pan the audio from -1.0 (left) to 1.0 (right) by attenuating the opposite channel.
+    pub fn pan(&mut self, pan: f32) {
+        // No need to do work if the audio is centered.
+        if pan == 0.0 {
             return;
         }
 
-        // Multiple every sample by the volume.
+        let left_gain = (1.0 - pan).min(1.0);
+        let right_gain = (1.0 + pan).min(1.0);
         self.buffer.iter_mut().for_each(|sample| {
-            *sample *= volume;
+            sample.left *= left_gain;
+            sample.right *= right_gain;
         });
     }
 
-    pub fn encode(&mut self, path: impl AsRef<Path>, mono: bool) -> Result<(), AudioError> {
-        // If we're encoding in mono, we'll need to tell the encoder.
-        let channels = if mono { 1 } else { 2 };
-        // Open the output file and setup the encoder to encode into it.
-        let file = File::create(path)?;
-        let mut encoder = VorbisEncoderBuilder::new(
-            NonZeroU32::new(self.sample_rate).ok_or(AudioError::InvalidCodecInfo())?,
-            NonZeroU8::new(channels as u8).ok_or(AudioError::InvalidCodecInfo())?,
-            file,
-        )?
-        .build()?;
-
-        // The audio buffer is not guaranteed to be divisible by the chunk size, which is
-        // required by the encoder. This is the remainder needed for padding.
-        let missing_samples = self.buffer.len() % ENCODING_CHUNK_SIZE;
-
-        // Pad both iterators with zeroes to meet the chunk size.
-        let mut left = self
-            .buffer
-            .iter()
-            .map(|sample| sample.left)
-            .chain((0..missing_samples).map(|_| Default::default()));
-        let mut right = self
-            .buffer
-            .iter()
-            .map(|sample| sample.right)
-            .chain((0..missing_samples).map(|_| Default::default()));
-
-        // Iterate over the length of the buffer. The iterators are padded with an amount less than the chunk size,
-        // so iterating to self.buffer.len() isn't an issue here.
-        for _ in (0..self.buffer.len()).step_by(ENCODING_CHUNK_SIZE) {
-            // Pull chunks from the iterators.
-            let Some(left_chunk): Option<[f32; ENCODING_CHUNK_SIZE]> = left.next_array() else {
-                continue;
-            };
-            let Some(right_chunk): Option<[f32; ENCODING_CHUNK_SIZE]> = right.next_array() else {
-                continue;
-            };
-
-            // If we're in stereo, we can just encode the two chunks normally in a block.
-            if !mono {
-                let block = &[left_chunk, right_chunk];
-
-                encoder.encode_audio_block(block)?;
-            } else {
-                // In mono, we need to average the samples, then encode.
-                let average: [f32; ENCODING_CHUNK_SIZE] = left_chunk
-                    .iter()
-                    .zip(right_chunk)
-                    .map(|(lhs, rhs)| (lhs + rhs) / 2.0)
-                    .collect_array()
-                    .unwrap();
-                let block = &[average];
-
-                encoder.encode_audio_block(block)?;
-            }
-        }
+    /// Cut the audio down to a maximum length.
+    pub fn truncate(&mut self, length: f64) {
+        let samples = self.time_to_samples(length).max(0) as usize;
+        self.buffer.truncate(samples);
+    }
 
-        Ok(())
+    /// Check if every sample of the audio is below the threshold of hearing.
+    pub fn is_silent(&self) -> bool {
+        // About -80 dBFS.
+        const SILENCE_THRESHOLD: f32 = 0.0001;
+
+        self.buffer.iter().all(|sample| {
+            sample.left.abs() < SILENCE_THRESHOLD && sample.right.abs() < SILENCE_THRESHOLD
+        })
     }
 
-    /// Match the sample rate of the passed audio via resampling.
-    pub fn match_sample_rate(&mut self, rhs: &StereoAudio) -> Result<(), AudioError> {
-        if self.sample_rate != rhs.sample_rate {
-            self.resample(rhs.sample_rate as usize)?;
+    pub fn attenuate(&mut self, volume: f32) {
+        // No need to do work if volume is 1.
+        if volume == 1.0 {
+            return;
         }
 
-        Ok(())
+        // Multiple every sample by the volume.
+        self.buffer.iter_mut().for_each(|sample| {
+            *sample *= volume;
+        });
+    }
+
+    pub fn encode(
+        &mut self,
+        path: impl AsRef<Path>,
+        format: OutputFormat,
+        settings: &EncoderSettings,
+    ) -> Result<(), AudioError> {
+        let mut encoder = create_encoder(path, format, self.sample_rate, settings)?;
+        encoder.write(&self.buffer)?;
+        encoder.finish()
     }
 
     /// Get the length of the audio.
-    #[allow(dead_code)]
     pub fn get_length(&self) -> f64 {
         self.samples_to_time(self.buffer.len() as isize)
     }
 
     /// Convert a number of samples into time based on sample rate.
-    #[allow(dead_code)]
     fn samples_to_time(&self, samples: isize) -> f64 {
-        return samples as f64 / self.sample_rate as f64;
+        samples as f64 / self.sample_rate as f64
     }
 
     /// Convert an amount of time into samples.
     fn time_to_samples(&self, time: f64) -> isize {
-        return (time * self.sample_rate as f64) as isize;
+        (time * self.sample_rate as f64) as isize
     }
 
     /// Get the number of samples per channel.
     fn samples_per_channel(&self) -> usize {
-        return self.buffer.len();
+        self.buffer.len()
     }
 }
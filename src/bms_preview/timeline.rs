@@ -0,0 +1,240 @@
+use bms_rs::bms::model::Bms;
+use bms_rs::bms::prelude::ObjTime;
+use clap::ValueEnum;
+
+const DEFAULT_BPM: f64 = 130.0;
+/// Measures are numbered with three digits, so a chart has at most 1000 of them.
+const MAX_MEASURES: usize = 1000;
+const BEATS_PER_MEASURE: f64 = 4.0;
+
+/// The grid that the preview window is snapped to.
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SnapMode {
+    /// Snap to the nearest measure line.
+    Measure,
+    /// Snap to the nearest beat.
+    Beat,
+    /// Snap to the nearest phrase, which is a group of measures.
+    Phrase,
+}
+
+/// A point where the tempo of a chart changes.
+struct TempoPoint {
+    beat: f64,
+    seconds: f64,
+    bpm: f64,
+}
+
+// referenced from https://github.com/approvers/bms-bounce/blob/master/bms-rs-wasm/src/lib.rs
+/// Converts positions in a chart into seconds, accounting for measure lengths and BPM changes.
+pub struct Timeline {
+    /// The beat at which each measure starts, with an extra entry for the end of the last measure.
+    measure_beats: Vec<f64>,
+    /// The tempo changes of the chart, sorted by beat. Always contains the initial tempo.
+    tempo_points: Vec<TempoPoint>,
+}
+
+impl Timeline {
+    /// Build the timeline of a chart.
+    pub fn new(bms: &Bms) -> Self {
+        // Get the length in beats of each measure, which defaults to four beats.
+        let mut measure_lengths = vec![BEATS_PER_MEASURE; MAX_MEASURES];
+        bms.section_len
+            .section_len_changes
+            .iter()
+            .for_each(|(track, obj)| {
+                let Some(length) = measure_lengths.get_mut(track.0 as usize) else {
+                    return;
+                };
+                let section_len: f64 = obj.length.clone().try_into().unwrap_or(1.0);
+                *length = section_len * BEATS_PER_MEASURE;
+            });
+
+        // Accumulate the lengths into the starting beat of each measure.
+        let mut measure_beats = Vec::with_capacity(MAX_MEASURES + 1);
+        measure_beats.push(0.0);
+        measure_lengths.iter().for_each(|length| {
+            measure_beats.push(measure_beats.last().unwrap() + length);
+        });
+
+        let initial_bpm: f64 = bms
+            .bpm
+            .bpm
+            .clone()
+            .and_then(|bpm| bpm.try_into().ok())
+            .filter(|bpm: &f64| *bpm > 0.0)
+            .unwrap_or(DEFAULT_BPM);
+
+        let mut timeline = Self {
+            measure_beats,
+            tempo_points: vec![TempoPoint {
+                beat: 0.0,
+                seconds: 0.0,
+                bpm: initial_bpm,
+            }],
+        };
+
+        // Integrate over the BPM changes to find the time at which each one happens.
+        bms.bpm.bpm_changes.iter().for_each(|(offset, change)| {
+            let Ok(bpm): Result<f64, _> = change.bpm.clone().try_into() else {
+                return;
+            };
+            if bpm <= 0.0 {
+                return;
+            }
+
+            let beat = timeline.beats(*offset);
+            let seconds = timeline.beats_to_seconds(beat);
+            timeline
+                .tempo_points
+                .push(TempoPoint { beat, seconds, bpm });
+        });
+
+        timeline
+    }
+
+    /// Build the timeline of a chart in 4/4 at a constant tempo.
+    #[cfg(test)]
+    pub fn with_bpm(bpm: f64) -> Self {
+        Self {
+            measure_beats: (0..=MAX_MEASURES)
+                .map(|measure| measure as f64 * BEATS_PER_MEASURE)
+                .collect(),
+            tempo_points: vec![TempoPoint {
+                beat: 0.0,
+                seconds: 0.0,
+                bpm,
+            }],
+        }
+    }
+
+    /// Get the position of an object in beats from the start of the chart.
+    pub fn beats(&self, offset: ObjTime) -> f64 {
+        let track = (offset.track().0 as usize).min(MAX_MEASURES - 1);
+        let measure_start = self.measure_beats[track];
+        let measure_length = self.measure_beats[track + 1] - measure_start;
+
+        measure_start + measure_length * offset.numerator() as f64 / offset.denominator_u64() as f64
+    }
+
+    /// Convert a position in beats into seconds from the start of the chart.
+    pub fn beats_to_seconds(&self, beat: f64) -> f64 {
+        // Find the last tempo change at or before this beat.
+        let index = self
+            .tempo_points
+            .partition_point(|point| point.beat <= beat)
+            .saturating_sub(1);
+        let point = &self.tempo_points[index];
+
+        point.seconds + (beat - point.beat) * 60.0 / point.bpm
+    }
+
+    /// Convert seconds from the start of the chart into a position in beats.
+    pub fn seconds_to_beats(&self, seconds: f64) -> f64 {
+        // Find the last tempo change at or before this time.
+        let index = self
+            .tempo_points
+            .partition_point(|point| point.seconds <= seconds)
+            .saturating_sub(1);
+        let point = &self.tempo_points[index];
+
+        point.beat + (seconds - point.seconds) * point.bpm / 60.0
+    }
+
+    /// Get the time in seconds of an object from the start of the chart.
+    pub fn seconds(&self, offset: ObjTime) -> f64 {
+        self.beats_to_seconds(self.beats(offset))
+    }
+
+    /// Convert a measure number into seconds. Fractional measures are positioned within the measure.
+    pub fn measure_to_seconds(&self, measure: f64) -> f64 {
+        let measure = measure.clamp(0.0, MAX_MEASURES as f64);
+        let index = (measure.floor() as usize).min(MAX_MEASURES - 1);
+        let measure_start = self.measure_beats[index];
+        let measure_length = self.measure_beats[index + 1] - measure_start;
+
+        self.beats_to_seconds(measure_start + measure_length * (measure - index as f64))
+    }
+
+    /// Convert seconds into a measure number. The position within the measure is the fractional part.
+    pub fn seconds_to_measure(&self, seconds: f64) -> f64 {
+        let beat = self.seconds_to_beats(seconds);
+        let index = self
+            .measure_beats
+            .partition_point(|measure_beat| *measure_beat <= beat)
+            .saturating_sub(1)
+            .min(MAX_MEASURES - 1);
+        let measure_start = self.measure_beats[index];
+        let measure_length = self.measure_beats[index + 1] - measure_start;
+
+        index as f64 + (beat - measure_start) / measure_length.max(f64::EPSILON)
+    }
+
+    /// Get the times of every line in a grid within the length of a song, in ascending order.
+    fn grid(&self, mode: SnapMode, phrase_length: usize, song_length: f64) -> Vec<f64> {
+        // Charts can have far more measures than the song lasts for, so stop at the end of the song.
+        let last_beat = self.seconds_to_beats(song_length);
+        let measures = self
+            .measure_beats
+            .iter()
+            .copied()
+            .take_while(|beat| *beat <= last_beat);
+
+        let beats: Vec<f64> = match mode {
+            SnapMode::Measure => measures.collect(),
+            SnapMode::Phrase => measures.step_by(phrase_length.max(1)).collect(),
+            SnapMode::Beat => {
+                // Beats are counted from the start of each measure, so shortened measures
+                // restart the count on the next measure line.
+                let mut beats = Vec::new();
+                self.measure_beats
+                    .windows(2)
+                    .take_while(|measure| measure[0] <= last_beat)
+                    .for_each(|measure| {
+                        let mut beat = measure[0];
+                        while beat < measure[1] && beat <= last_beat {
+                            beats.push(beat);
+                            beat += 1.0;
+                        }
+                    });
+                beats
+            }
+        };
+
+        beats
+            .into_iter()
+            .map(|beat| self.beats_to_seconds(beat))
+            .collect()
+    }
+
+    /// Snap the start and end of a window to the nearest lines of a grid, up to the end of the song.
+    /// The end is kept after the start, even if they snap to the same line.
+    pub fn snap_window(
+        &self,
+        start: f64,
+        end: f64,
+        mode: SnapMode,
+        phrase_length: usize,
+        song_length: f64,
+    ) -> (f64, f64) {
+        let grid = self.grid(mode, phrase_length, song_length);
+        let nearest = |time: f64| {
+            grid.iter()
+                .copied()
+                .min_by(|a, b| (a - time).abs().total_cmp(&(b - time).abs()))
+                .unwrap_or(time)
+        };
+
+        let start = nearest(start);
+        let mut end = nearest(end);
+        if end <= start {
+            end = grid
+                .iter()
+                .copied()
+                .find(|line| *line > start)
+                .unwrap_or(end);
+        }
+
+        (start, end)
+    }
+}
@@ -0,0 +1,383 @@
+use std::str::FromStr;
+
+use crate::bms_preview::PreviewSettings;
+use crate::bms_preview::errors::WindowError;
+use crate::bms_preview::timeline::Timeline;
+
+const DEFAULT_START: f64 = 20.0;
+const DEFAULT_DURATION: f64 = 20.0;
+
+/// A point in a song, given in seconds, as a percentage of the song, or as a measure.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimePoint {
+    /// Seconds from the start of the song, written as `12.5` or `12.5s`.
+    Seconds(f64),
+    /// A percentage of the length of the song, written as `40%`.
+    Percent(f64),
+    /// A measure of the chart, written as `#16`.
+    Measure(f64),
+}
+
+impl FromStr for TimePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parse = |value: &str| {
+            value
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("invalid time '{}'", s))
+        };
+
+        if let Some(percent) = s.strip_suffix('%') {
+            Ok(TimePoint::Percent(parse(percent)?))
+        } else if let Some(measure) = s.strip_prefix('#') {
+            Ok(TimePoint::Measure(parse(measure)?))
+        } else {
+            Ok(TimePoint::Seconds(parse(s.strip_suffix('s').unwrap_or(s))?))
+        }
+    }
+}
+
+impl TimePoint {
+    /// Convert the point into seconds from the start of the song.
+    pub fn resolve(&self, song_length: f64, timeline: &Timeline) -> f64 {
+        match self {
+            TimePoint::Seconds(seconds) => *seconds,
+            TimePoint::Percent(percent) => percent / 100.0 * song_length,
+            TimePoint::Measure(measure) => timeline.measure_to_seconds(*measure),
+        }
+    }
+}
+
+/// The length of a window, in seconds or measures.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Duration {
+    Seconds(f64),
+    Measures(f64),
+}
+
+/// The preview window as requested, which can be any combination of a start, end and duration.
+/// Whatever isn't given is filled in from defaults.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WindowSpec {
+    pub start: Option<TimePoint>,
+    pub end: Option<TimePoint>,
+    pub duration: Option<Duration>,
+}
+
+/// Get the only value that is set, if any, or an error if there are several.
+fn only_one<T>(
+    values: impl IntoIterator<Item = Option<T>>,
+    error: WindowError,
+) -> Result<Option<T>, WindowError> {
+    let mut set = values.into_iter().flatten();
+    let value = set.next();
+
+    match set.next() {
+        Some(_) => Err(error),
+        None => Ok(value),
+    }
+}
+
+impl WindowSpec {
+    /// Get the window from the settings, checking that they don't contradict each other.
+    pub fn from_settings(settings: &PreviewSettings) -> Result<Self, WindowError> {
+        let start = only_one(
+            [
+                settings.start.map(TimePoint::Seconds),
+                settings.start_p.map(TimePoint::Percent),
+                settings.start_measure.map(TimePoint::Measure),
+            ],
+            WindowError::ConflictingStart(),
+        )?;
+        let end = only_one(
+            [
+                settings.end.map(TimePoint::Seconds),
+                settings.end_p.map(TimePoint::Percent),
+                settings.end_measure.map(TimePoint::Measure),
+            ],
+            WindowError::ConflictingEnd(),
+        )?;
+        let duration = only_one(
+            [
+                settings.duration.map(Duration::Seconds),
+                settings.measures.map(Duration::Measures),
+            ],
+            WindowError::ConflictingEnd(),
+        )?;
+
+        let spec = WindowSpec {
+            start,
+            end,
+            duration,
+        };
+        spec.validate()?;
+
+        Ok(spec)
+    }
+
+    /// Check that the values of the window make sense on their own.
+    fn validate(&self) -> Result<(), WindowError> {
+        if self.end.is_some() && self.duration.is_some() {
+            return Err(match self.start {
+                Some(_) => WindowError::OverdeterminedWindow(),
+                None => WindowError::ConflictingEnd(),
+            });
+        }
+
+        let is_invalid_percentage = |point: &Option<TimePoint>| match point {
+            Some(TimePoint::Percent(percent)) => !(0.0..=100.0).contains(percent),
+            _ => false,
+        };
+        if is_invalid_percentage(&self.start) || is_invalid_percentage(&self.end) {
+            return Err(WindowError::InvalidPercentage());
+        }
+
+        if let Some(Duration::Seconds(length) | Duration::Measures(length)) = self.duration
+            && length <= 0.0
+        {
+            return Err(WindowError::InvalidDuration());
+        }
+
+        // Points in different units can only be compared once the song is known.
+        match (self.start, self.end) {
+            (Some(TimePoint::Seconds(start)), Some(TimePoint::Seconds(end)))
+            | (Some(TimePoint::Percent(start)), Some(TimePoint::Percent(end)))
+            | (Some(TimePoint::Measure(start)), Some(TimePoint::Measure(end)))
+                if end <= start =>
+            {
+                Err(WindowError::EmptyWindow())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Convert the window into a start and end in seconds.
+    pub fn resolve(
+        &self,
+        song_length: f64,
+        timeline: &Timeline,
+    ) -> Result<(f64, f64), WindowError> {
+        let start = self.start.map(|point| point.resolve(song_length, timeline));
+        let end = self.end.map(|point| point.resolve(song_length, timeline));
+
+        // Measure durations are counted from the measure that the window starts or ends in.
+        let after = |start: f64| match self.duration {
+            Some(Duration::Seconds(length)) => start + length,
+            Some(Duration::Measures(measures)) => {
+                timeline.measure_to_seconds(timeline.seconds_to_measure(start) + measures)
+            }
+            None => start + DEFAULT_DURATION,
+        };
+        let before = |end: f64| match self.duration {
+            Some(Duration::Seconds(length)) => end - length,
+            Some(Duration::Measures(measures)) => {
+                timeline.measure_to_seconds(timeline.seconds_to_measure(end) - measures)
+            }
+            // A start that only comes from the default length mustn't push an explicit end later.
+            None => (end - DEFAULT_DURATION).max(0.0),
+        };
+
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            (Some(start), None) => (start, after(start)),
+            (None, Some(end)) => (before(end), end),
+            (None, None) => (DEFAULT_START, after(DEFAULT_START)),
+        };
+
+        check_order(start, end)
+    }
+}
+
+/// Check that a window in seconds ends after it starts, which points in different units can only be
+/// checked for once they're resolved.
+fn check_order(start: f64, end: f64) -> Result<(f64, f64), WindowError> {
+    if end <= start {
+        return Err(WindowError::EndsBeforeStart(start, end));
+    }
+
+    Ok((start, end))
+}
+
+/// Fit a window within the length of a song, shifting it to keep its length where possible.
+pub fn fit_window(start: f64, end: f64, song_length: f64) -> (f64, f64) {
+    let length = (end - start).min(song_length).max(0.0);
+    let start = start.clamp(0.0, (song_length - length).max(0.0));
+
+    (start, start + length)
+}
+
+/// A section of a song between two points, written as `START-END`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub start: TimePoint,
+    pub end: TimePoint,
+}
+
+impl FromStr for Segment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("segment '{}' should be in the form START-END", s))?;
+
+        Ok(Segment {
+            start: start.parse()?,
+            end: end.parse()?,
+        })
+    }
+}
+
+impl Segment {
+    /// Convert the segment into a start and end in seconds.
+    pub fn resolve(
+        &self,
+        song_length: f64,
+        timeline: &Timeline,
+    ) -> Result<(f64, f64), WindowError> {
+        let start = self.start.resolve(song_length, timeline);
+        let end = self.end.resolve(song_length, timeline);
+
+        check_order(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(set: impl FnOnce(&mut PreviewSettings)) -> Result<WindowSpec, WindowError> {
+        let mut settings = PreviewSettings::default();
+        set(&mut settings);
+        WindowSpec::from_settings(&settings)
+    }
+
+    #[test]
+    fn defaults_leave_everything_unset() {
+        assert_eq!(
+            spec(|_| {}).unwrap(),
+            WindowSpec {
+                start: None,
+                end: None,
+                duration: None,
+            }
+        );
+    }
+
+    #[test]
+    fn points_keep_their_units() {
+        let window = spec(|settings| {
+            settings.start_p = Some(25.0);
+            settings.measures = Some(8.0);
+        })
+        .unwrap();
+
+        assert_eq!(window.start, Some(TimePoint::Percent(25.0)));
+        assert_eq!(window.end, None);
+        assert_eq!(window.duration, Some(Duration::Measures(8.0)));
+    }
+
+    #[test]
+    fn conflicting_points_are_rejected() {
+        assert!(matches!(
+            spec(|settings| {
+                settings.start = Some(10.0);
+                settings.start_measure = Some(4.0);
+            }),
+            Err(WindowError::ConflictingStart())
+        ));
+        assert!(matches!(
+            spec(|settings| {
+                settings.duration = Some(10.0);
+                settings.measures = Some(4.0);
+            }),
+            Err(WindowError::ConflictingEnd())
+        ));
+        assert!(matches!(
+            spec(|settings| {
+                settings.end = Some(30.0);
+                settings.duration = Some(10.0);
+            }),
+            Err(WindowError::ConflictingEnd())
+        ));
+    }
+
+    #[test]
+    fn overdetermined_window_is_rejected() {
+        assert!(matches!(
+            spec(|settings| {
+                settings.start = Some(10.0);
+                settings.end = Some(30.0);
+                settings.duration = Some(20.0);
+            }),
+            Err(WindowError::OverdeterminedWindow())
+        ));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(matches!(
+            spec(|settings| settings.end_p = Some(120.0)),
+            Err(WindowError::InvalidPercentage())
+        ));
+        assert!(matches!(
+            spec(|settings| settings.duration = Some(0.0)),
+            Err(WindowError::InvalidDuration())
+        ));
+        assert!(matches!(
+            spec(|settings| {
+                settings.start_measure = Some(16.0);
+                settings.end_measure = Some(8.0);
+            }),
+            Err(WindowError::EmptyWindow())
+        ));
+    }
+
+    #[test]
+    fn early_end_shortens_the_default_window() {
+        let timeline = Timeline::with_bpm(120.0);
+        let window = spec(|settings| settings.end = Some(10.0)).unwrap();
+
+        // The end is kept when the window is fitted, rather than the default length.
+        let (start, end) = window.resolve(60.0, &timeline).unwrap();
+        assert_eq!((start, end), (0.0, 10.0));
+        assert_eq!(fit_window(start, end, 60.0), (0.0, 10.0));
+    }
+
+    #[test]
+    fn late_end_keeps_the_default_length() {
+        let timeline = Timeline::with_bpm(120.0);
+        let window = spec(|settings| settings.end = Some(40.0)).unwrap();
+
+        assert_eq!(window.resolve(60.0, &timeline).unwrap(), (20.0, 40.0));
+    }
+
+    #[test]
+    fn measures_are_counted_from_the_start() {
+        let timeline = Timeline::with_bpm(120.0);
+        let window = spec(|settings| {
+            settings.start_measure = Some(4.0);
+            settings.measures = Some(4.0);
+        })
+        .unwrap();
+
+        // Measures last two seconds at 120 BPM.
+        assert_eq!(window.resolve(60.0, &timeline).unwrap(), (8.0, 16.0));
+    }
+
+    #[test]
+    fn windows_must_end_after_they_start() {
+        assert_eq!(check_order(10.0, 30.0).unwrap(), (10.0, 30.0));
+        assert!(matches!(
+            check_order(30.0, 10.0),
+            Err(WindowError::EndsBeforeStart(..))
+        ));
+        assert!(matches!(
+            check_order(10.0, 10.0),
+            Err(WindowError::EndsBeforeStart(..))
+        ));
+    }
+}
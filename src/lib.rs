@@ -0,0 +1,6 @@
+//! Generates audio previews of BMS charts, either for a whole songs folder or one chart at a time.
+//! Charts are loaded with [`Renderer::new`], and previews can be written into their song folder or
+//! rendered into memory with [`PreviewSettings`], which default to the command line's defaults.
+mod bms_preview;
+
+pub use bms_preview::*;
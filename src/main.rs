@@ -1,8 +1,6 @@
-mod bms_preview;
-
 use colored::Colorize;
 
-use crate::bms_preview::*;
+use bms_preview_generator::*;
 
 use std::path::Path;
 